@@ -1,9 +1,18 @@
-use crate::run::{execute_command, Function, RunErrorKind as Error, State, Value};
+use crate::json;
+use crate::parse::{self, is_block_keyword};
+use crate::run::{
+    execute_command, execute_with_state, Callable, Function, RunErrorKind as Error, State, Value,
+    MAX_DEPTH, MAX_LISTS,
+};
 use std::cell::{RefCell, RefMut};
+use std::collections::{HashMap, HashSet};
 use std::fmt::Write;
+use std::path::Path;
 use std::rc::Rc;
-use std::time::SystemTime;
-use Value::{Lineptr, List, Number, String as StringVal};
+use std::sync::OnceLock;
+use std::time::{Instant, SystemTime};
+use std::{fs, iter};
+use Value::{Func, Lineptr, List, Number, String as StringVal, Void};
 
 fn frombool(boole: bool) -> Value {
     Number(boole as i32 as f64)
@@ -16,7 +25,7 @@ impl Value {
             StringVal(s) => s
                 .parse::<f64>()
                 .map_err(|_| Error::IsNotNumber(self.clone())),
-            List(_) => Err(Error::IsNotNumber(self.clone())),
+            List(_) | Func(_) | Void => Err(Error::IsNotNumber(self.clone())),
             Number(n) => Ok(*n),
             Lineptr(_) => panic!(),
         }
@@ -29,13 +38,61 @@ impl Value {
         }
     }
 
+    /// converts a single-character string to a char
+    fn tochar(&self) -> Result<char, Error> {
+        let string = self.tostr();
+        let mut iter = string.chars();
+        let chr = iter
+            .next()
+            .ok_or_else(|| Error::OrdError(Rc::clone(&string)))?;
+        if iter.next().is_some() {
+            return Err(Error::OrdError(Rc::clone(&string)));
+        };
+        Ok(chr)
+    }
+
     fn toindex(&self) -> Result<usize, Error> {
         (self.tonum()?.floor() as usize)
             .checked_sub(1)
             .ok_or(Error::ZeroIndex)
     }
 
-    fn tolist(&self) -> Result<RefMut<Vec<Value>>, Error> {
+    /// converts the value to an index of a list of length `len`, erroring if it's out of range.
+    /// negative indices count from the end, so -1 is the last item
+    fn tolistindex(&self, len: usize) -> Result<usize, Error> {
+        let num = self.tonum()?.floor();
+        let index = if num < 0f64 {
+            len as f64 + num
+        } else {
+            num - 1f64
+        };
+        if num == 0f64 {
+            Err(Error::ZeroIndex)
+        } else if !(0f64..len as f64).contains(&index) {
+            // this also catches NaN, which would otherwise be cast to 0
+            Err(Error::IndexError(num, len))
+        } else {
+            Ok(index as usize)
+        }
+    }
+
+    /// whether the value is "truthy". numbers (and strings that are numbers) are true unless they
+    /// are zero, other strings and lists are true unless they are empty, and void is false
+    fn tobool(&self) -> bool {
+        match self {
+            StringVal(s) => match s.parse::<f64>() {
+                Ok(n) => n != 0f64,
+                Err(_) => !s.is_empty(),
+            },
+            List(l) => !l.borrow().is_empty(),
+            Number(n) => *n != 0f64,
+            Func(_) => true,
+            Void => false,
+            Lineptr(_) => panic!(),
+        }
+    }
+
+    fn tolist(&self) -> Result<RefMut<'_, Vec<Value>>, Error> {
         match self {
             List(l) => Ok(l.borrow_mut()),
             _ => Err(Error::IsNotList(self.clone())),
@@ -47,13 +104,207 @@ fn eq(a: &Value, b: &Value) -> bool {
     match &[a, b] {
         [List(l), List(m)] => {
             let (l, m) = (l.borrow(), m.borrow());
-            l.iter().zip(m.iter()).all(|(x, y)| eq(x, y))
+            l.len() == m.len() && l.iter().zip(m.iter()).all(|(x, y)| eq(x, y))
         }
         [Number(x), Number(y)] => x == y,
         [x, y] => x.tostr() == y.tostr(),
     }
 }
 
+/// copies `v` and every list inside of it. `depth` is how many lists it is inside of, which is
+/// limited so it can't overflow the stack
+fn deepclone(v: &Value, depth: usize) -> Result<Value, Error> {
+    Ok(match v {
+        List(_) if depth >= MAX_DEPTH => return Err(Error::DepthError),
+        List(l) => List(Rc::new(RefCell::new(
+            (l.borrow().iter())
+                .map(|x| deepclone(x, depth + 1))
+                .collect::<Result<_, _>>()?,
+        ))),
+        other => other.clone(),
+    })
+}
+
+/// checks that a string of `count` pieces of `size` bytes could be made, before trying to
+/// allocate it
+fn checklen(count: usize, size: usize) -> Result<(), Error> {
+    match count.checked_mul(size) {
+        Some(len) if len <= isize::MAX as usize => Ok(()),
+        _ => Err(Error::TooLongError),
+    }
+}
+
+/// 32-bit FNV-1a. it's 32 bits so that the result fits exactly in a float
+fn fnv1a(bytes: &[u8]) -> u32 {
+    let mut hash = 0x811c9dc5u32;
+    for byte in bytes {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    hash
+}
+
+/// splits a unix time into (year, month, day, hour, minute, second), in UTC. this is the
+/// `civil_from_days` algorithm from http://howardhinnant.github.io/date_algorithms.html
+fn datetime(time: f64) -> (i64, i64, i64, i64, i64, i64) {
+    let time = time.floor() as i64;
+    let (days, secs) = (time.div_euclid(86400), time.rem_euclid(86400));
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    (year, month, day, secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+fn flattendeep(v: &Value, out: &mut Vec<Value>) {
+    match v {
+        List(l) => l.borrow().iter().for_each(|x| flattendeep(x, out)),
+        other => out.push(other.clone()),
+    }
+}
+
+/// the items of a list of numbers
+fn numbers(l: &Value) -> Result<Vec<f64>, Error> {
+    l.tolist()?.iter().map(Value::tonum).collect()
+}
+
+/// like `tolistindex`, but the error says if it was the row or the column that was out of range
+fn gridindex(i: &Value, len: usize, what: &'static str) -> Result<usize, Error> {
+    i.tolistindex(len).map_err(|e| match e {
+        Error::IndexError(i, len) => Error::GridIndexError(what, i, len),
+        e => e,
+    })
+}
+
+/// like `Display`, but every item of a list goes in its own line, indented by its depth. `left`
+/// is how many more lists can be shown, also like `Display`
+fn pretty(v: &Value, depth: usize, left: &mut usize, out: &mut String) {
+    match v {
+        List(_) if depth >= MAX_DEPTH || *left == 0 => out.push_str("(...)"),
+        List(l) if l.borrow().is_empty() => out.push_str("()"),
+        List(l) => {
+            *left -= 1;
+            out.push_str("(\n");
+            let borrow = l.borrow();
+            for (i, item) in borrow.iter().enumerate() {
+                out.push_str(&"    ".repeat(depth + 1));
+                pretty(item, depth + 1, left, out);
+                out.push_str(if i + 1 < borrow.len() { ",\n" } else { "\n" });
+            }
+            out.push_str(&"    ".repeat(depth));
+            out.push(')');
+        }
+        other => write!(out, "{}", other).unwrap(),
+    }
+}
+
+/// whether `v` is the list `list` or has it somewhere inside, including inside of function values
+/// made with `_compose` or `_partial`. `seen` has the lists already checked, so lists that appear
+/// many times are only searched once
+fn contains(
+    v: &Value,
+    list: &Rc<RefCell<Vec<Value>>>,
+    seen: &mut HashSet<*const RefCell<Vec<Value>>>,
+) -> bool {
+    match v {
+        List(l) if Rc::ptr_eq(l, list) => true,
+        List(l) if seen.insert(Rc::as_ptr(l)) => l.borrow().iter().any(|x| contains(x, list, seen)),
+        Func(func) => match func.as_ref() {
+            Callable::Named(_) => false,
+            Callable::Compose(first, second) => {
+                contains(first, list, seen) || contains(second, list, seen)
+            }
+            Callable::Partial(func, args) => {
+                contains(func, list, seen) || args.iter().any(|x| contains(x, list, seen))
+            }
+        },
+        _ => false,
+    }
+}
+
+/// lists can't contain themselves, or displaying or comparing them would never end. this
+/// errors if putting `v` inside of `l` would do that
+fn nocycle(l: &Value, v: &Value) -> Result<(), Error> {
+    match l {
+        List(l) if contains(v, l, &mut HashSet::new()) => Err(Error::CycleError),
+        _ => Ok(()),
+    }
+}
+
+/// the key in `state.functions` of the function made with `define` called `name`, if there is one
+fn definekey(state: &State, name: &str) -> Option<Rc<str>> {
+    let call = state.calls.get(name)?;
+    state.functions.contains_key(call).then(|| Rc::clone(call))
+}
+
+/// calls a function value, or a command by its name
+fn apply(state: &mut State, f: &Value, args: &[Value]) -> Result<Value, Error> {
+    match f {
+        Func(func) => match func.as_ref() {
+            Callable::Named(name) => execute_command(state, name, args),
+            Callable::Compose(first, second) => {
+                let result = apply(state, second, args)?;
+                apply(state, first, &[result])
+            }
+            Callable::Partial(func, fixed) => apply(state, func, &[fixed, args].concat()),
+        },
+        other => execute_command(state, &other.tostr(), args),
+    }
+}
+
+/// the variables sorted by name, so that listing them always gives the same order
+fn sorted(vars: &HashMap<Rc<str>, Value>) -> Vec<(&Rc<str>, &Value)> {
+    let mut vars = vars.iter().collect::<Vec<_>>();
+    vars.sort_unstable_by_key(|(k, _)| *k);
+    vars
+}
+
+#[cfg(feature = "regex")]
+fn regex(pattern: &Value) -> Result<regex::Regex, Error> {
+    regex::Regex::new(&pattern.tostr()).map_err(Error::RegexError)
+}
+
+/// greatest common divisor, of the integer part of the absolute values
+fn gcd(a: f64, b: f64) -> f64 {
+    let (mut a, mut b) = (a.abs().floor(), b.abs().floor());
+    if !a.is_finite() || !b.is_finite() {
+        return f64::NAN;
+    }
+    while b != 0f64 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+fn lcm(a: f64, b: f64) -> f64 {
+    let (a, b) = (a.abs().floor(), b.abs().floor());
+    if a == 0f64 || b == 0f64 {
+        0f64
+    } else {
+        a / gcd(a, b) * b
+    }
+}
+
+/// like `eq`, but values of different types are never equal
+fn eqstrict(a: &Value, b: &Value) -> bool {
+    match &[a, b] {
+        [List(l), List(m)] => {
+            let (l, m) = (l.borrow(), m.borrow());
+            l.len() == m.len() && l.iter().zip(m.iter()).all(|(x, y)| eqstrict(x, y))
+        }
+        [Number(x), Number(y)] => x == y,
+        [StringVal(x), StringVal(y)] => x == y,
+        [Func(x), Func(y)] => x == y,
+        [Void, Void] => true,
+        _ => false,
+    }
+}
+
 pub fn builtins<'a>(state: &'a mut State, name: &str, args: &'a [Value]) -> Result<Value, Error> {
     let mut args = args;
     // a command with a fixed (as in, not variadic) number of arguments.
@@ -94,32 +345,106 @@ pub fn builtins<'a>(state: &'a mut State, name: &str, args: &'a [Value]) -> Resu
                 _ => return Err(Error::ValueError(1)),
             },
             "end while" => fixed!([], {
-                state.lineno = lineptr - 1;
+                // the line number gets incremented right after this. the block can start at line
+                // 0, so this has to wrap around
+                state.lineno = lineptr.wrapping_sub(1);
+                Value::default()
+            }),
+            "end repeat" => fixed!([], {
+                state.lineno = lineptr.wrapping_sub(1);
                 Value::default()
             }),
             "end if" => fixed!([], Value::default()),
             "if" | "while" => fixed!([cond], {
-                if cond.tonum()? == 0f64 {
+                if !cond.tobool() {
                     state.lineno = *lineptr;
                 }
                 Value::default()
             }),
+            "repeat" => fixed!([count], {
+                // the count is only evaluated when entering the block. after that, `count` is void
+                // and the stored number is used
+                let left = match state.repeats.get(&state.lineno) {
+                    Some(left) => *left,
+                    None => count.tonum()?.floor() as usize,
+                };
+                if left == 0 {
+                    state.repeats.remove(&state.lineno);
+                    state.lineno = *lineptr;
+                } else {
+                    state.repeats.insert(state.lineno, left - 1);
+                }
+                Value::default()
+            }),
+            "switch" => fixed!([value], {
+                state.switches.insert(state.lineno, value.clone());
+                state.lineno = lineptr - 1;
+                Value::default()
+            }),
+            "case" | "default" => {
+                let (switchno, value) = match args {
+                    [x, Lineptr(switchno)] if name == "case" => (switchno, Some(x)),
+                    [Lineptr(switchno)] if name == "default" => (switchno, None),
+                    _ => return Err(Error::ValueError((name == "case") as usize)),
+                };
+                // if the switch isn't there, an earlier case matched, and its body just ended
+                let matched =
+                    (state.switches.get(switchno)).is_some_and(|v| value.is_none_or(|x| eq(v, x)));
+                if matched {
+                    state.switches.remove(switchno);
+                } else {
+                    state.lineno = lineptr - 1;
+                }
+                Value::default()
+            }
+            "end switch" => {
+                state.switches.remove(lineptr);
+                Value::default()
+            }
             "_cmd" => {
                 if args.len() <= 1 {
                     return Err(Error::ValueError(1));
                 }
                 let name = &args[0];
-                let arguments = &args[1..].iter().map(Value::tostr).collect::<Vec<_>>();
-                let arguments = arguments
-                    .first()
-                    .map_or(false, |x| !str::eq(x, "...")) // ?????
-                    .then(|| Rc::from(&arguments[..]));
+                // these would be parsed as blocks, so the function would never be called
+                if is_block_keyword(&name.tostr()) {
+                    return Err(Error::ReservedName(name.tostr()));
+                }
+                let names = args[1..].iter().map(Value::tostr).collect::<Vec<_>>();
+                let mut arguments = None;
+                let mut required = 0;
+                let mut rest = None;
+                if names.first().is_some_and(|x| !str::eq(x, "...")) {
+                    // arguments that end with `?` are optional. they can only go at the end, only
+                    // followed by an argument ending in `...` that gets the rest as a list
+                    let mut fargs = Vec::with_capacity(names.len());
+                    for (n, arg) in names.iter().enumerate() {
+                        if let Some(arg) = arg.strip_suffix("...") {
+                            if n != names.len() - 1 {
+                                return Err(Error::RestOrder(Rc::from(arg)));
+                            }
+                            rest = Some(Rc::from(arg));
+                        } else if let Some(arg) = arg.strip_suffix('?') {
+                            fargs.push(Rc::from(arg));
+                        } else if required < fargs.len() {
+                            return Err(Error::OptionalOrder(Rc::clone(arg)));
+                        } else {
+                            fargs.push(Rc::clone(arg));
+                            required += 1;
+                        }
+                    }
+                    arguments = Some(Rc::from(fargs));
+                }
                 if (state.functions)
                     .insert(
                         name.tostr(),
                         Function {
+                            lines: Rc::clone(&state.lines),
+                            file: state.file.clone(),
                             lineno: state.lineno + 1,
                             arguments,
+                            required,
+                            rest,
                         },
                     )
                     .is_some()
@@ -130,17 +455,26 @@ pub fn builtins<'a>(state: &'a mut State, name: &str, args: &'a [Value]) -> Resu
                 Value::default()
             }
             "define" => fixed!([name], {
+                let name = name.tostr();
+                let call = Rc::clone(
+                    (state.calls.entry(Rc::clone(&name)))
+                        .or_insert_with(|| Rc::from("call ".to_string() + &name)),
+                );
                 if (state.functions)
                     .insert(
-                        Rc::from("call ".to_string() + &name.tostr()),
+                        call,
                         Function {
+                            lines: Rc::clone(&state.lines),
+                            file: state.file.clone(),
                             lineno: state.lineno + 1,
                             arguments: None,
+                            required: 0,
+                            rest: None,
                         },
                     )
                     .is_some()
                 {
-                    return Err(Error::FuncDefined(name.tostr()));
+                    return Err(Error::FuncDefined(name));
                 };
                 state.lineno = *lineptr;
                 Value::default()
@@ -164,21 +498,91 @@ pub fn builtins<'a>(state: &'a mut State, name: &str, args: &'a [Value]) -> Resu
                 .reduce(|x, y| Ok(x? * y?))
                 .unwrap_or(Ok(1f64))?,
         ),
+        "_sum" => fixed!([l], {
+            Number(
+                (l.tolist()?.iter())
+                    .map(Value::tonum)
+                    .reduce(|x, y| Ok(x? + y?))
+                    .unwrap_or(Ok(0f64))?,
+            )
+        }),
+        "_product" => fixed!([l], {
+            Number(
+                (l.tolist()?.iter())
+                    .map(Value::tonum)
+                    .reduce(|x, y| Ok(x? * y?))
+                    .unwrap_or(Ok(1f64))?,
+            )
+        }),
         "sub" => dyad!(<f64 as std::ops::Sub>::sub),
         "div" => dyad!(<f64 as std::ops::Div>::div),
         "mod" => dyad!(<f64 as std::ops::Rem>::rem),
+        "_idiv" => dyad!(|x: f64, y: f64| (x / y).floor()),
+        "_divmod" => fixed!([x, y], {
+            let (x, y) = (x.tonum()?, y.tonum()?);
+            let quotient = (x / y).floor();
+            List(Rc::new(RefCell::new(vec![
+                Number(quotient),
+                Number(x - quotient * y),
+            ])))
+        }),
+        "_modeuclid" => dyad!(f64::rem_euclid),
         "_pow" => dyad!(f64::powf),
         "_floor" => monad!(f64::floor),
-        "_round" => monad!(f64::round),
+        "_ceil" => monad!(f64::ceil),
+        "_trunc" => monad!(f64::trunc),
+        "_round" => match args {
+            [_] => monad!(f64::round),
+            [_, _] => dyad!(|x: f64, places: f64| {
+                let factor = 10f64.powf(places.floor());
+                // with that many places there is nothing to round, and it would overflow. with
+                // that many negative places, `factor` is 0 and everything rounds to 0
+                if !(x * factor).is_finite() {
+                    return x;
+                } else if factor == 0f64 {
+                    return 0f64;
+                }
+                (x * factor).round() / factor
+            }),
+            _ => return Err(Error::ValueRangeError(1, 2)),
+        },
         "_sqrt" => monad!(f64::sqrt),
+        "_cbrt" => monad!(f64::cbrt),
+        "_hypot" => dyad!(f64::hypot),
+        "_degrees" => monad!(f64::to_degrees),
+        "_radians" => monad!(f64::to_radians),
         "_sin" => monad!(f64::sin),
         "_cos" => monad!(f64::cos),
         "_tan" => monad!(f64::tan),
         "_asin" => monad!(f64::asin),
         "_acos" => monad!(f64::acos),
         "_atan" => monad!(f64::atan),
+        "_atan2" => dyad!(f64::atan2),
         "_ln" => monad!(f64::ln),
         "_exp" => monad!(f64::exp),
+        "_pi" => fixed!([], Number(std::f64::consts::PI)),
+        "_e" => fixed!([], Number(std::f64::consts::E)),
+        "_clamp" => fixed!([x, lo, hi], {
+            let (lo, hi) = (lo.tonum()?, hi.tonum()?);
+            // `f64::clamp` panics with these
+            if lo > hi || lo.is_nan() || hi.is_nan() {
+                return Err(Error::ClampError(lo, hi));
+            }
+            Number(x.tonum()?.clamp(lo, hi))
+        }),
+        "_gcd" => dyad!(gcd),
+        "_lcm" => dyad!(lcm),
+        "_isint" => monad!(|x: f64| (x.fract() == 0f64) as i64),
+        "_isnum" => fixed!([x], frombool(x.tonum().is_ok())),
+        "_isnan" => monad!(|x: f64| x.is_nan() as i64),
+        "_isinf" => monad!(|x: f64| x.is_infinite() as i64),
+        "_isfinite" => monad!(|x: f64| x.is_finite() as i64),
+        "_bool" => fixed!([x], frombool(x.tobool())),
+        "_void" => fixed!([], Void),
+        "_isvoid" => fixed!([x], frombool(matches!(x, Void))),
+        "_tonumber" => fixed!([x], Number(x.tonum()?)),
+        "_tostring" => fixed!([x], StringVal(x.tostr())),
+        "_parsenum" => fixed!([x], x.tonum().map_or_else(|_| Value::default(), Number)),
         "len" => fixed!([i], {
             match i {
                 List(l) => Number(l.borrow().len() as _),
@@ -186,14 +590,15 @@ pub fn builtins<'a>(state: &'a mut State, name: &str, args: &'a [Value]) -> Resu
             }
         }),
         "eq" => fixed!([x, y], frombool(eq(x, y))),
-        "not" => monad!(|x| (x == 0f64) as i64),
+        "_eqstrict" => fixed!([x, y], frombool(eqstrict(x, y))),
+        "not" => fixed!([x], frombool(!x.tobool())),
         "lt" => dyad!(|x, y| (x < y) as i64),
         "gt" => dyad!(|x, y| (x > y) as i64),
         "lte" => dyad!(|x, y| (x <= y) as i64),
         "gte" => dyad!(|x, y| (x >= y) as i64),
         "or" => {
             for arg in args {
-                if arg.tonum()? != 0f64 {
+                if arg.tobool() {
                     return Ok(Number(1f64));
                 }
             }
@@ -201,7 +606,7 @@ pub fn builtins<'a>(state: &'a mut State, name: &str, args: &'a [Value]) -> Resu
         }
         "and" => {
             for arg in args {
-                if arg.tonum()? == 0f64 {
+                if !arg.tobool() {
                     return Ok(Number(0f64));
                 }
             }
@@ -221,8 +626,14 @@ pub fn builtins<'a>(state: &'a mut State, name: &str, args: &'a [Value]) -> Resu
             for v in args.iter() {
                 print!("{}", v)
             }
+            // stdout is only flushed on newlines
+            std::io::Write::flush(&mut std::io::stdout()).map_err(Error::IOError)?;
             Value::default()
         }
+        "_flush" => fixed!([], {
+            std::io::Write::flush(&mut std::io::stdout()).map_err(Error::IOError)?;
+            Value::default()
+        }),
         "_printerr" => {
             for (n, v) in args.iter().enumerate() {
                 if n == args.len() - 1 {
@@ -240,14 +651,57 @@ pub fn builtins<'a>(state: &'a mut State, name: &str, args: &'a [Value]) -> Resu
             Value::default()
         }
         "input" => fixed!([], {
+            // the newline is kept, so a blank line is "\n", and "" always means end of input
             let mut buffer = String::new();
             std::io::stdin()
                 .read_line(&mut buffer)
                 .map_err(Error::IOError)?;
             StringVal(Rc::from(buffer))
         }),
+        "_readall" => fixed!([], {
+            let mut buffer = String::new();
+            std::io::Read::read_to_string(&mut std::io::stdin(), &mut buffer)
+                .map_err(Error::IOError)?;
+            StringVal(Rc::from(buffer))
+        }),
+        "_lines" => fixed!([], {
+            let mut buffer = String::new();
+            std::io::Read::read_to_string(&mut std::io::stdin(), &mut buffer)
+                .map_err(Error::IOError)?;
+            List(Rc::new(RefCell::new(
+                (buffer.lines()).map(|x| StringVal(Rc::from(x))).collect(),
+            )))
+        }),
+        "_load" => fixed!([path], {
+            let path = path.tostr();
+            // every file runs at most once, so files that load each other don't loop forever
+            let canonical = Path::new(&*path).canonicalize().map_err(Error::IOError)?;
+            if !state.loaded.insert(canonical) {
+                return Ok(frombool(false));
+            }
+            let code = fs::read_to_string(&*path).map_err(Error::IOError)?;
+            let lines = parse::parse(&code).map_err(Error::ParseError)?;
+            // it shares the globals and functions, but its top level gets its own locals
+            let mut state = State {
+                globals: state.globals,
+                locals: &mut HashMap::new(),
+                functions: state.functions,
+                repeats: &mut HashMap::new(),
+                switches: &mut HashMap::new(),
+                loaded: state.loaded,
+                memos: state.memos,
+                calls: state.calls,
+                lineno: 0,
+                lines: Rc::from(lines),
+                file: Some(Rc::clone(&path)),
+            };
+            execute_with_state(&mut state).map_err(|e| Error::InFile(Some(path), Box::new(e)))?;
+            frombool(true)
+        }),
         "substr" => fixed!([s, x, y], {
-            let (start, stop) = (x.toindex()?, y.toindex()? + 1);
+            // `stop` is inclusive, so it is already the number of chars to keep from the start.
+            // anything out of range just gets clamped, and `start > stop` gives an empty string
+            let (start, stop) = (x.toindex()?, y.tonum()?.floor() as usize);
             StringVal(Rc::from(
                 s.tostr()
                     .chars()
@@ -256,6 +710,31 @@ pub fn builtins<'a>(state: &'a mut State, name: &str, args: &'a [Value]) -> Resu
                     .collect::<String>(),
             ))
         }),
+        "_startswith" => fixed!([s, x], frombool(s.tostr().starts_with(x.tostr().as_ref()))),
+        "_endswith" => fixed!([s, x], frombool(s.tostr().ends_with(x.tostr().as_ref()))),
+        "_repeat" => fixed!([s, n], {
+            // negative numbers (and NaN) saturate to 0
+            let (s, n) = (s.tostr(), n.tonum()?.floor() as usize);
+            checklen(n, s.len())?;
+            StringVal(Rc::from(s.repeat(n)))
+        }),
+        "_hash" => fixed!([x], Number(fnv1a(x.tostr().as_bytes()) as f64)),
+        "_padleft" | "_padright" => {
+            let (s, width, fill) = match args {
+                [s, width] => (s.tostr(), width, ' '),
+                [s, width, fill] => (s.tostr(), width, fill.tochar()?),
+                _ => return Err(Error::ValueRangeError(2, 3)),
+            };
+            let width = width.tonum()?.floor() as usize;
+            let count = width.saturating_sub(s.chars().count());
+            checklen(count, fill.len_utf8())?;
+            let padding = iter::repeat_n(fill, count);
+            StringVal(Rc::from(if name == "_padleft" {
+                padding.chain(s.chars()).collect::<String>()
+            } else {
+                s.chars().chain(padding).collect::<String>()
+            }))
+        }
         "_chr" => fixed!([x], {
             let num = x.tonum()?.floor() as u32;
             StringVal(Rc::from(
@@ -264,16 +743,33 @@ pub fn builtins<'a>(state: &'a mut State, name: &str, args: &'a [Value]) -> Resu
                     .to_string(),
             ))
         }),
-        "_ord" => fixed!([x], {
-            let string = x.tostr();
-            let mut iter = string.chars();
-            let chr = iter
-                .next()
-                .ok_or_else(|| Error::OrdError(Rc::clone(&string)))?;
-            if iter.next().is_some() {
-                return Err(Error::OrdError(Rc::clone(&string)));
-            };
-            Number(chr as u32 as f64)
+        "_ord" => fixed!([x], Number(x.tochar()? as u32 as f64)),
+        "_chars" => fixed!([x], {
+            List(Rc::new(RefCell::new(
+                (x.tostr().chars())
+                    .map(|c| StringVal(Rc::from(c.to_string())))
+                    .collect(),
+            )))
+        }),
+        "_bytes" => fixed!([x], {
+            List(Rc::new(RefCell::new(
+                (x.tostr().bytes()).map(|b| Number(b as f64)).collect(),
+            )))
+        }),
+        "_frombytes" => fixed!([l], {
+            let bytes = (l.tolist()?.iter())
+                .map(|x| {
+                    let num = x.tonum()?.floor();
+                    // checked before casting, since NaN would become 0
+                    if !(0f64..=255f64).contains(&num) {
+                        return Err(Error::ByteError(num));
+                    }
+                    Ok(num as u8)
+                })
+                .collect::<Result<Vec<u8>, _>>()?;
+            StringVal(Rc::from(
+                String::from_utf8(bytes).map_err(Error::Utf8Error)?,
+            ))
         }),
         "join" => {
             let mut string = String::new();
@@ -282,21 +778,95 @@ pub fn builtins<'a>(state: &'a mut State, name: &str, args: &'a [Value]) -> Resu
             }
             StringVal(Rc::from(string))
         }
+        "_format" => {
+            let (template, mut values) = match args.split_first() {
+                Some((template, values)) => (template.tostr(), values.iter()),
+                None => return Err(Error::ValueAtLeastError(1)),
+            };
+            let mut string = String::new();
+            let mut chars = template.chars().peekable();
+            while let Some(chr) = chars.next() {
+                match (chr, chars.peek()) {
+                    ('{', Some('{')) | ('}', Some('}')) => {
+                        chars.next();
+                        string.push(chr);
+                    }
+                    ('{', Some('}')) => {
+                        chars.next();
+                        let value = (values.next())
+                            .ok_or(Error::FormatError("more `{}` than arguments"))?;
+                        write!(&mut string, "{}", value).unwrap();
+                    }
+                    ('{' | '}', _) => {
+                        return Err(Error::FormatError("unmatched brace in the template"))
+                    }
+                    (chr, _) => string.push(chr),
+                }
+            }
+            if values.next().is_some() {
+                return Err(Error::FormatError("more arguments than `{}`"));
+            }
+            StringVal(Rc::from(string))
+        }
+        "_splitn" => fixed!([s, d, n], {
+            // negative counts saturate to 0, which gives no pieces at all
+            let n = n.tonum()?.floor() as usize;
+            List(Rc::new(RefCell::new(
+                (s.tostr().splitn(n, &*d.tostr()))
+                    .map(|x| StringVal(Rc::from(x)))
+                    .collect(),
+            )))
+        }),
+        "_tojson" => fixed!([x], {
+            let mut string = String::new();
+            json::tojson(x, &mut string)?;
+            StringVal(Rc::from(string))
+        }),
+        "_pretty" => fixed!([x], {
+            let mut string = String::new();
+            pretty(x, 0, &mut { MAX_LISTS }, &mut string);
+            StringVal(Rc::from(string))
+        }),
+        "_fromjson" => fixed!([x], json::fromjson(&x.tostr())?),
         "list" => List(Rc::from(RefCell::from(args.to_vec()))),
         "index" => fixed!([l, i], {
             let list = l.tolist()?;
-            let index = i.toindex()?;
-            list.get(index)
-                .ok_or(Error::IndexError(index, list.len()))?
-                .clone()
+            list[i.tolistindex(list.len())?].clone()
+        }),
+        "_get2d" => fixed!([l, r, c], {
+            let grid = l.tolist()?;
+            let row = grid[gridindex(r, grid.len(), "row")?].tolist()?;
+            row[gridindex(c, row.len(), "column")?].clone()
+        }),
+        "_set2d" => fixed!([l, r, c, v], {
+            let row = {
+                let grid = l.tolist()?;
+                grid[gridindex(r, grid.len(), "row")?].clone()
+            };
+            nocycle(&row, v)?;
+            let mut row = row.tolist()?;
+            let index = gridindex(c, row.len(), "column")?;
+            row[index] = v.clone();
+            Value::default()
         }),
         "push" => fixed!([l, v], {
+            nocycle(l, v)?;
             let mut borrow = l.tolist()?;
             borrow.push(v.clone());
             Value::default()
         }),
+        "_extend" => fixed!([l, m], {
+            // cloned first, because `l` and `m` could be the same list
+            let items = m.tolist()?.clone();
+            for item in &items {
+                nocycle(l, item)?;
+            }
+            l.tolist()?.extend(items);
+            Value::default()
+        }),
         "pop" => fixed!([l], l.tolist()?.pop().ok_or(Error::PopError)?),
         "insert" => fixed!([l, i, v], {
+            nocycle(l, v)?;
             let mut borrow = l.tolist()?;
             let index = borrow.len().min(i.toindex()?);
             borrow.insert(index, v.clone());
@@ -304,17 +874,197 @@ pub fn builtins<'a>(state: &'a mut State, name: &str, args: &'a [Value]) -> Resu
         }),
         "remove" => fixed!([l, i], {
             let mut borrow = l.tolist()?;
-            let len = borrow.len();
-            let index = len.min(i.toindex()?);
+            let index = i.tolistindex(borrow.len())?;
             borrow.remove(index)
         }),
         "replace" => fixed!([l, i, v], {
+            nocycle(l, v)?;
+            let mut borrow = l.tolist()?;
+            let index = i.tolistindex(borrow.len())?;
+            borrow[index] = v.clone();
+            Value::default()
+        }),
+        "_swap" => fixed!([l, i, j], {
             let mut borrow = l.tolist()?;
-            let index = i.toindex()?;
             let len = borrow.len();
-            *borrow.get_mut(index).ok_or(Error::IndexError(index, len))? = v.clone();
+            borrow.swap(i.tolistindex(len)?, j.tolistindex(len)?);
+            Value::default()
+        }),
+        "_find" => fixed!([f, l], {
+            // cloned, because the command could change the list
+            let items = l.tolist()?.clone();
+            for item in items {
+                if apply(state, f, std::slice::from_ref(&item))?.tobool() {
+                    return Ok(item);
+                }
+            }
             Value::default()
         }),
+        "_all" => fixed!([f, l], {
+            let items = l.tolist()?.clone();
+            for item in items {
+                if !apply(state, f, &[item])?.tobool() {
+                    return Ok(Number(0f64));
+                }
+            }
+            Number(1f64)
+        }),
+        "_any" => fixed!([f, l], {
+            let items = l.tolist()?.clone();
+            for item in items {
+                if apply(state, f, &[item])?.tobool() {
+                    return Ok(Number(1f64));
+                }
+            }
+            Number(0f64)
+        }),
+        "_vadd" | "_vsub" | "_dot" => fixed!([l, m], {
+            let (l, m) = (numbers(l)?, numbers(m)?);
+            if l.len() != m.len() {
+                return Err(Error::LengthError(l.len(), m.len()));
+            }
+            let pairs = l.into_iter().zip(m);
+            match name {
+                "_vadd" => List(Rc::new(RefCell::new(
+                    pairs.map(|(x, y)| Number(x + y)).collect(),
+                ))),
+                "_vsub" => List(Rc::new(RefCell::new(
+                    pairs.map(|(x, y)| Number(x - y)).collect(),
+                ))),
+                _ => Number(pairs.map(|(x, y)| x * y).sum()),
+            }
+        }),
+        "_vscale" => fixed!([l, k], {
+            let k = k.tonum()?;
+            List(Rc::new(RefCell::new(
+                numbers(l)?.into_iter().map(|x| Number(x * k)).collect(),
+            )))
+        }),
+        "_partition" => fixed!([f, l], {
+            let items = l.tolist()?.clone();
+            let (mut yes, mut no) = (Vec::new(), Vec::new());
+            for item in items {
+                if apply(state, f, std::slice::from_ref(&item))?.tobool() {
+                    yes.push(item);
+                } else {
+                    no.push(item);
+                }
+            }
+            List(Rc::new(RefCell::new(vec![
+                List(Rc::new(RefCell::new(yes))),
+                List(Rc::new(RefCell::new(no))),
+            ])))
+        }),
+        "_maxby" | "_minby" => fixed!([f, l], {
+            let items = l.tolist()?.clone();
+            let mut best: Option<(f64, Value)> = None;
+            for item in items {
+                let key = apply(state, f, std::slice::from_ref(&item))?.tonum()?;
+                // only strictly better keys replace it, so the first one wins on ties
+                let better = match &best {
+                    None => true,
+                    Some((k, _)) if name == "_maxby" => key > *k,
+                    Some((k, _)) => key < *k,
+                };
+                if better {
+                    best = Some((key, item));
+                }
+            }
+            best.map(|(_, item)| item).unwrap_or_default()
+        }),
+        "_flatten" => fixed!([l], {
+            let mut items = Vec::new();
+            for item in l.tolist()?.iter() {
+                match item {
+                    List(m) => items.extend(m.borrow().iter().cloned()),
+                    other => items.push(other.clone()),
+                }
+            }
+            List(Rc::new(RefCell::new(items)))
+        }),
+        "_flattendeep" => fixed!([l], {
+            let mut items = Vec::new();
+            for item in l.tolist()?.iter() {
+                flattendeep(item, &mut items);
+            }
+            List(Rc::new(RefCell::new(items)))
+        }),
+        "_zip" => fixed!([l, m], {
+            // cloned, because `l` and `m` could be the same list
+            let first = l.tolist()?.clone();
+            let pairs = (first.into_iter())
+                .zip(m.tolist()?.iter().cloned())
+                .map(|(x, y)| List(Rc::new(RefCell::new(vec![x, y]))))
+                .collect();
+            List(Rc::new(RefCell::new(pairs)))
+        }),
+        "_first" => fixed!([l], l.tolist()?.first().cloned().ok_or(Error::EmptyError)?),
+        "_last" => fixed!([l], l.tolist()?.last().cloned().ok_or(Error::EmptyError)?),
+        "_rest" => fixed!([l], {
+            let list = l.tolist()?;
+            List(Rc::new(RefCell::new(
+                list.get(1..).unwrap_or_default().to_vec(),
+            )))
+        }),
+        "_init" => fixed!([l], {
+            let list = l.tolist()?;
+            let len = list.len().saturating_sub(1);
+            List(Rc::new(RefCell::new(list[..len].to_vec())))
+        }),
+        "_take" | "_drop" => fixed!([l, n], {
+            let list = l.tolist()?;
+            // negative numbers saturate to 0
+            let n = (n.tonum()?.floor() as usize).min(list.len());
+            let items = if name == "_take" {
+                &list[..n]
+            } else {
+                &list[n..]
+            };
+            List(Rc::new(RefCell::new(items.to_vec())))
+        }),
+        "_chunk" | "_windows" => fixed!([l, n], {
+            let size = n.tonum()?.floor();
+            if size < 1f64 || size.is_nan() {
+                return Err(Error::SizeError(size));
+            }
+            let list = l.tolist()?;
+            let chunks = if name == "_chunk" {
+                list.chunks(size as usize).collect::<Vec<_>>()
+            } else {
+                list.windows(size as usize).collect()
+            };
+            let chunks = (chunks.into_iter())
+                .map(|chunk| List(Rc::new(RefCell::new(chunk.to_vec()))))
+                .collect();
+            List(Rc::new(RefCell::new(chunks)))
+        }),
+        "_enumerate" => fixed!([l], {
+            let pairs = (l.tolist()?.iter().enumerate())
+                .map(|(i, x)| {
+                    List(Rc::new(RefCell::new(vec![
+                        Number(i as f64 + 1f64),
+                        x.clone(),
+                    ])))
+                })
+                .collect();
+            List(Rc::new(RefCell::new(pairs)))
+        }),
+        "_unzip" => fixed!([l], {
+            let (mut first, mut second) = (Vec::new(), Vec::new());
+            for pair in l.tolist()?.iter() {
+                match pair.tolist()?.as_slice() {
+                    [x, y, ..] => {
+                        first.push(x.clone());
+                        second.push(y.clone());
+                    }
+                    other => return Err(Error::IndexError(2f64, other.len())),
+                }
+            }
+            List(Rc::new(RefCell::new(vec![
+                List(Rc::new(RefCell::new(first))),
+                List(Rc::new(RefCell::new(second))),
+            ])))
+        }),
         "_islist" => fixed!([x], Number(matches!(x, List(_)) as i64 as f64)),
         "_clone" => fixed!([x], {
             match x {
@@ -322,6 +1072,38 @@ pub fn builtins<'a>(state: &'a mut State, name: &str, args: &'a [Value]) -> Resu
                 other => other.clone(),
             }
         }),
+        "_deepclone" => fixed!([x], deepclone(x, 0)?),
+        "_unique" => fixed!([l], {
+            // this is O(n²), because values can't be hashed (and `eq` is a bit weird anyways)
+            let mut unique: Vec<Value> = Vec::new();
+            for item in l.tolist()?.iter() {
+                if !unique.iter().any(|x| eq(x, item)) {
+                    unique.push(item.clone());
+                }
+            }
+            List(Rc::new(RefCell::new(unique)))
+        }),
+        "_tally" => fixed!([l], {
+            // O(n²) like `_unique`
+            let mut tally: Vec<(Value, usize)> = Vec::new();
+            for item in l.tolist()?.iter() {
+                match tally.iter_mut().find(|(x, _)| eq(x, item)) {
+                    Some((_, count)) => *count += 1,
+                    None => tally.push((item.clone(), 1)),
+                }
+            }
+            List(Rc::new(RefCell::new(
+                (tally.into_iter())
+                    .map(|(x, n)| List(Rc::new(RefCell::new(vec![x, Number(n as f64)]))))
+                    .collect(),
+            )))
+        }),
+        "_count" => fixed!([l, x], {
+            match l {
+                List(l) => Number(l.borrow().iter().filter(|y| eq(x, y)).count() as _),
+                s => Number(s.tostr().matches(x.tostr().as_ref()).count() as _),
+            }
+        }),
         "set" => fixed!([l, r], {
             let l = l.tostr();
             if l.starts_with('.') {
@@ -331,6 +1113,23 @@ pub fn builtins<'a>(state: &'a mut State, name: &str, args: &'a [Value]) -> Resu
             }
             Value::default()
         }),
+        // these ignore the sigil, and add or remove the dot so the variable can be read back
+        "_setglobal" => fixed!([l, r], {
+            let l = l.tostr();
+            let name = l.strip_prefix('.').map_or(Rc::clone(&l), Rc::from);
+            state.globals.insert(name, r.clone());
+            Value::default()
+        }),
+        "_setlocal" => fixed!([l, r], {
+            let l = l.tostr();
+            let name = if l.starts_with('.') {
+                l
+            } else {
+                Rc::from(format!(".{}", l))
+            };
+            state.locals.insert(name, r.clone());
+            Value::default()
+        }),
         "_get" => fixed!([v], {
             let s = v.tostr();
             let var = if s.starts_with('.') {
@@ -340,29 +1139,111 @@ pub fn builtins<'a>(state: &'a mut State, name: &str, args: &'a [Value]) -> Resu
             };
             var.cloned().ok_or(Error::NameError(s))?
         }),
+        "_unset" => fixed!([v], {
+            let s = v.tostr();
+            let var = if s.starts_with('.') {
+                state.locals.remove(s.as_ref())
+            } else {
+                state.globals.remove(s.as_ref())
+            };
+            frombool(var.is_some())
+        }),
         "_globals" => fixed!([], {
             List(Rc::new(RefCell::new(
-                (state.globals.keys())
-                    .map(|x| StringVal(Rc::clone(x)))
+                (sorted(state.globals).into_iter())
+                    .map(|(k, _)| StringVal(Rc::clone(k)))
                     .collect(),
             )))
         }),
         "_locals" => fixed!([], {
             List(Rc::new(RefCell::new(
-                (state.locals.keys())
-                    .map(|x| StringVal(Rc::clone(x)))
+                (sorted(state.locals).into_iter())
+                    .map(|(k, _)| StringVal(Rc::clone(k)))
+                    .collect(),
+            )))
+        }),
+        "_dumpglobals" => fixed!([], {
+            List(Rc::new(RefCell::new(
+                (sorted(state.globals).into_iter())
+                    .map(|(k, v)| {
+                        List(Rc::new(RefCell::new(vec![
+                            StringVal(Rc::clone(k)),
+                            v.clone(),
+                        ])))
+                    })
+                    .collect(),
+            )))
+        }),
+        "_dumplocals" => fixed!([], {
+            List(Rc::new(RefCell::new(
+                (sorted(state.locals).into_iter())
+                    .map(|(k, v)| {
+                        List(Rc::new(RefCell::new(vec![
+                            StringVal(Rc::clone(k)),
+                            v.clone(),
+                        ])))
+                    })
                     .collect(),
             )))
         }),
         "_error" => fixed!([e], return Err(Error::UserError(e.tostr()))),
-        "call" => execute_command(
-            state,
-            &("call ".to_string() + &(args.get(0).ok_or(Error::ValueError(1))?).tostr()),
-            &args[1..],
-        )?,
-        "_apply" => fixed!([n, a], {
-            execute_command(state, n.tostr().as_ref(), a.tolist()?.as_slice())?
+        "_exit" => match args {
+            [] => return Err(Error::Exit(0)),
+            [code] => return Err(Error::Exit(code.tonum()?.floor() as i32)),
+            _ => return Err(Error::ValueRangeError(0, 1)),
+        },
+        "call" => {
+            let first = args.first().ok_or(Error::ValueError(1))?;
+            if let Func(_) = first {
+                return apply(state, first, &args[1..]);
+            }
+            // functions made with `define` go first, but builtins and `_cmd` commands work too
+            let name = first.tostr();
+            let name = definekey(state, &name).unwrap_or(name);
+            execute_command(state, &name, &args[1..])?
+        }
+        "_isdefined" => fixed!([n], {
+            let n = n.tostr();
+            frombool(state.functions.contains_key(&n) || definekey(state, &n).is_some())
+        }),
+        "_memoize" => fixed!([n], {
+            let n = n.tostr();
+            let name = if state.functions.contains_key(&n) {
+                n
+            } else if let Some(call) = definekey(state, &n) {
+                call
+            } else {
+                return Err(Error::NotDefined);
+            };
+            state.memos.entry(name).or_default();
+            Value::default()
+        }),
+        "_apply" => fixed!([f, a], {
+            // cloned, because the command could change the list
+            let args = a.tolist()?.clone();
+            apply(state, f, &args)?
+        }),
+        "_fn" => fixed!([n], {
+            let n = n.tostr();
+            let name = if state.functions.contains_key(&n) {
+                n
+            } else if let Some(call) = definekey(state, &n) {
+                call
+            } else {
+                // there's no way to check if it's a builtin without running it
+                n
+            };
+            Func(Rc::new(Callable::Named(name)))
         }),
+        "_compose" => fixed!(
+            [f, g],
+            Func(Rc::new(Callable::Compose(f.clone(), g.clone())))
+        ),
+        "_partial" => {
+            let (f, fixed) = args.split_first().ok_or(Error::ValueAtLeastError(1))?;
+            Func(Rc::new(Callable::Partial(f.clone(), Rc::from(fixed))))
+        }
+        "_isfn" => fixed!([x], frombool(matches!(x, Func(_)))),
         "_return" => {
             return Err(match args {
                 [] => Error::Return(Value::default()),
@@ -378,6 +1259,39 @@ pub fn builtins<'a>(state: &'a mut State, name: &str, args: &'a [Value]) -> Resu
                     .as_secs_f64(),
             )
         }),
+        "_monotonic" => fixed!([], {
+            // `Instant`s can only be compared with each other, so it counts from the first call
+            static START: OnceLock<Instant> = OnceLock::new();
+            Number(START.get_or_init(Instant::now).elapsed().as_secs_f64())
+        }),
+        "_datefmt" => fixed!([t, f], {
+            let (year, month, day, hour, minute, second) = datetime(t.tonum()?);
+            let mut string = String::new();
+            let f = f.tostr();
+            let mut chars = f.chars();
+            while let Some(chr) = chars.next() {
+                if chr != '%' {
+                    string.push(chr);
+                    continue;
+                }
+                match chars.next() {
+                    Some('Y') => write!(string, "{:04}", year),
+                    Some('m') => write!(string, "{:02}", month),
+                    Some('d') => write!(string, "{:02}", day),
+                    Some('H') => write!(string, "{:02}", hour),
+                    Some('M') => write!(string, "{:02}", minute),
+                    Some('S') => write!(string, "{:02}", second),
+                    Some('%') => write!(string, "%"),
+                    Some(other) => write!(string, "%{}", other),
+                    None => write!(string, "%"),
+                }
+                .unwrap();
+            }
+            StringVal(Rc::from(string))
+        }),
+        "_year" => fixed!([t], Number(datetime(t.tonum()?).0 as f64)),
+        "_month" => fixed!([t], Number(datetime(t.tonum()?).1 as f64)),
+        "_day" => fixed!([t], Number(datetime(t.tonum()?).2 as f64)),
         "_rand" => {
             #[cfg(feature = "fastrand")]
             let val = fixed!([], Ok(Number(fastrand::f64())));
@@ -387,15 +1301,229 @@ pub fn builtins<'a>(state: &'a mut State, name: &str, args: &'a [Value]) -> Resu
         }
         "_random" => {
             #[cfg(feature = "fastrand")]
-            let val = fixed!([i, j], {
-                let (i, j) = (i.tonum()?.floor() as i64, j.tonum()?.floor() as i64);
+            let val = {
+                // with one argument, it's between 1 and it, like the indices of a list
+                let (i, j) = match args {
+                    [j] => (1, j.tonum()?.floor() as i64),
+                    [i, j] => (i.tonum()?.floor() as i64, j.tonum()?.floor() as i64),
+                    _ => return Err(Error::ValueRangeError(1, 2)),
+                };
+                if i > j {
+                    return Err(Error::RandomError(i, j));
+                }
                 Ok(Number(fastrand::i64(i..=j) as f64))
+            };
+            #[cfg(not(feature = "fastrand"))]
+            let val = Err(Error::RandUnavailable);
+            val?
+        }
+        "_seed" => {
+            #[cfg(feature = "fastrand")]
+            let val = fixed!([n], {
+                fastrand::seed(n.tonum()?.floor() as u64);
+                Ok(Value::default())
             });
             #[cfg(not(feature = "fastrand"))]
             let val = Err(Error::RandUnavailable);
             val?
         }
-        "end" | "while" | "if" | "define" | "_cmd" => return Err(Error::MustBeTopLevel),
+        "_shuffle" => {
+            #[cfg(feature = "fastrand")]
+            let val = fixed!([l], {
+                fastrand::shuffle(&mut l.tolist()?);
+                Ok(Value::default())
+            });
+            #[cfg(not(feature = "fastrand"))]
+            let val = Err(Error::RandUnavailable);
+            val?
+        }
+        "_choice" => {
+            #[cfg(feature = "fastrand")]
+            let val = fixed!([l], {
+                let list = l.tolist()?;
+                if list.is_empty() {
+                    return Err(Error::EmptyError);
+                }
+                Ok(list[fastrand::usize(..list.len())].clone())
+            });
+            #[cfg(not(feature = "fastrand"))]
+            let val = Err(Error::RandUnavailable);
+            val?
+        }
+        "_sample" => {
+            #[cfg(feature = "fastrand")]
+            let val = fixed!([l, n], {
+                let mut items = l.tolist()?.clone();
+                let n = n.tonum()?.floor() as usize;
+                if n > items.len() {
+                    return Err(Error::SampleError(n, items.len()));
+                }
+                // a fisher-yates shuffle that stops after the first `n` items
+                let len = items.len();
+                for i in 0..n {
+                    items.swap(i, fastrand::usize(i..len));
+                }
+                items.truncate(n);
+                Ok(List(Rc::new(RefCell::new(items))))
+            });
+            #[cfg(not(feature = "fastrand"))]
+            let val = Err(Error::RandUnavailable);
+            val?
+        }
+        "_rematch" => {
+            #[cfg(feature = "regex")]
+            let val = fixed!([s, p], Ok(frombool(regex(p)?.is_match(&s.tostr()))));
+            #[cfg(not(feature = "regex"))]
+            let val = Err(Error::RegexUnavailable);
+            val?
+        }
+        "_refind" => {
+            #[cfg(feature = "regex")]
+            let val = fixed!([s, p], {
+                let s = s.tostr();
+                let found = regex(p)?.find(&s).map(|m| m.as_str()).unwrap_or_default();
+                Ok(StringVal(Rc::from(found)))
+            });
+            #[cfg(not(feature = "regex"))]
+            let val = Err(Error::RegexUnavailable);
+            val?
+        }
+        "_rereplace" => {
+            #[cfg(feature = "regex")]
+            let val = fixed!([s, p, r], {
+                let replaced = regex(p)?.replace_all(&s.tostr(), &*r.tostr()).into_owned();
+                Ok(StringVal(Rc::from(replaced)))
+            });
+            #[cfg(not(feature = "regex"))]
+            let val = Err(Error::RegexUnavailable);
+            val?
+        }
+        "_resplit" => {
+            #[cfg(feature = "regex")]
+            let val = fixed!([s, p], {
+                let pieces = regex(p)?
+                    .split(&s.tostr())
+                    .map(|x| StringVal(Rc::from(x)))
+                    .collect();
+                Ok(List(Rc::new(RefCell::new(pieces))))
+            });
+            #[cfg(not(feature = "regex"))]
+            let val = Err(Error::RegexUnavailable);
+            val?
+        }
+        name if is_block_keyword(name) => return Err(Error::MustBeTopLevel),
         _ => return Err(Error::IsNotBuiltIn),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(name: &str, args: &[Value]) -> Result<Value, Error> {
+        let mut state = State {
+            globals: &mut HashMap::new(),
+            locals: &mut HashMap::new(),
+            functions: &mut HashMap::new(),
+            repeats: &mut HashMap::new(),
+            switches: &mut HashMap::new(),
+            loaded: &mut HashSet::new(),
+            memos: &mut HashMap::new(),
+            calls: &mut HashMap::new(),
+            lineno: 0,
+            lines: Rc::from([]),
+            file: None,
+        };
+        builtins(&mut state, name, args)
+    }
+
+    fn substr(s: &str, x: f64, y: f64) -> Result<Value, Error> {
+        call("substr", &[string(s), Number(x), Number(y)])
+    }
+
+    fn string(s: &str) -> Value {
+        StringVal(Rc::from(s))
+    }
+
+    fn list(items: Vec<Value>) -> Value {
+        List(Rc::new(RefCell::new(items)))
+    }
+
+    /// runs a program, and returns the status it passed to `_exit`
+    fn run(code: &str) -> i32 {
+        crate::run::execute(Rc::from(parse::parse(code).unwrap()), None).unwrap()
+    }
+
+    #[test]
+    fn case_compares_lists() {
+        let code = "switch (list 1 2)
+            case (list 1)
+                _exit 1
+            case (list 1 2)
+                _exit 2
+            default
+                _exit 3
+        end";
+        assert_eq!(run(code), 2);
+        assert_eq!(run(&code.replacen("(list 1 2)", "(list 3)", 1)), 3);
+    }
+
+    #[test]
+    fn apply_calls_builtins() {
+        let numbers = list(vec![Number(1f64), Number(2f64), Number(3f64)]);
+        assert_eq!(
+            call("_apply", &[string("add"), numbers]).unwrap(),
+            Number(6f64)
+        );
+        let lists = list(vec![list(vec![string("a"), string("b")])]);
+        assert_eq!(
+            call("_apply", &[string("len"), lists]).unwrap(),
+            Number(2f64)
+        );
+    }
+
+    #[test]
+    fn eq_compares_lengths() {
+        let (short, long) = (
+            list(vec![Number(1f64)]),
+            list(vec![Number(1f64), Number(2f64)]),
+        );
+        assert!(!eq(&short, &long));
+        assert!(!eq(&list(vec![]), &long));
+        assert!(eq(&long, &list(vec![string("1"), Number(2f64)])));
+        let unique = call("_unique", &[list(vec![long.clone(), short.clone()])]).unwrap();
+        assert_eq!(unique, list(vec![long, short]));
+    }
+
+    #[test]
+    fn substr_in_range() {
+        assert_eq!(substr("hello", 2f64, 4f64).unwrap(), string("ell"));
+        assert_eq!(substr("hello", 3f64, 3f64).unwrap(), string("l"));
+    }
+
+    #[test]
+    fn substr_reversed_is_empty() {
+        assert_eq!(substr("hello", 4f64, 2f64).unwrap(), string(""));
+    }
+
+    #[test]
+    fn substr_clamps_past_the_end() {
+        assert_eq!(substr("hello", 3f64, 100f64).unwrap(), string("llo"));
+        assert_eq!(substr("hello", 1f64, 1e300).unwrap(), string("hello"));
+        assert_eq!(substr("hello", 10f64, 20f64).unwrap(), string(""));
+    }
+
+    #[test]
+    fn substr_counts_chars() {
+        assert_eq!(
+            substr("héllo wörld", 2f64, 8f64).unwrap(),
+            string("éllo wö")
+        );
+        assert_eq!(substr("日本語", 2f64, 3f64).unwrap(), string("本語"));
+    }
+
+    #[test]
+    fn substr_starts_at_one() {
+        assert!(matches!(substr("hello", 0f64, 2f64), Err(Error::ZeroIndex)));
+    }
+}
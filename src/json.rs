@@ -0,0 +1,283 @@
+use crate::run::{RunErrorKind as Error, Value, MAX_DEPTH};
+use std::cell::RefCell;
+use std::fmt::Write;
+use std::iter::Peekable;
+use std::rc::Rc;
+use std::str::Chars;
+use Value::{Func, Lineptr, List, Number, String as StringVal, Void};
+
+pub fn tojson(v: &Value, out: &mut String) -> Result<(), Error> {
+    tojson_nested(v, out, 0)
+}
+
+/// `depth` is how many lists `v` is inside of. like `fromjson`, it stops at `MAX_DEPTH` so it
+/// can't overflow the stack
+fn tojson_nested(v: &Value, out: &mut String, depth: usize) -> Result<(), Error> {
+    match v {
+        List(_) if depth >= MAX_DEPTH => return Err(Error::DepthError),
+        Func(f) => tojson_nested(&StringVal(Rc::from(f.to_string())), out, depth)?,
+        StringVal(s) => {
+            out.push('"');
+            for c in s.chars() {
+                match c {
+                    '"' => out.push_str("\\\""),
+                    '\\' => out.push_str("\\\\"),
+                    '\n' => out.push_str("\\n"),
+                    '\r' => out.push_str("\\r"),
+                    '\t' => out.push_str("\\t"),
+                    c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+                    c => out.push(c),
+                }
+            }
+            out.push('"');
+        }
+        List(l) => {
+            out.push('[');
+            for (n, item) in l.borrow().iter().enumerate() {
+                if n != 0 {
+                    out.push(',');
+                }
+                tojson_nested(item, out, depth + 1)?;
+            }
+            out.push(']');
+        }
+        Void => out.push_str("null"),
+        // json doesn't have NaN or infinities
+        Number(n) if !n.is_finite() => out.push_str("null"),
+        Number(n) => write!(out, "{}", n).unwrap(),
+        Lineptr(_) => panic!(),
+    }
+    Ok(())
+}
+
+pub fn fromjson(s: &str) -> Result<Value, Error> {
+    let mut parser = Parser {
+        chars: s.chars().peekable(),
+        pos: 0,
+        depth: 0,
+    };
+    let value = parser.value()?;
+    parser.whitespace();
+    match parser.next() {
+        None => Ok(value),
+        Some(_) => Err(parser.error("unexpected character after the value")),
+    }
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    // how many characters have been consumed
+    pos: usize,
+    // how many arrays and objects the parser is inside of. it is limited, so that parsing can't
+    // overflow the stack
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn next(&mut self) -> Option<char> {
+        self.pos += 1;
+        self.chars.next()
+    }
+
+    fn error(&self, msg: &'static str) -> Error {
+        Error::JsonError(self.pos, msg)
+    }
+
+    fn whitespace(&mut self) {
+        while matches!(self.chars.peek(), Some(' ' | '\n' | '\r' | '\t')) {
+            self.next();
+        }
+    }
+
+    fn keyword(&mut self, word: &str, value: Value) -> Result<Value, Error> {
+        for chr in word.chars() {
+            if self.next() != Some(chr) {
+                return Err(self.error("unexpected character"));
+            }
+        }
+        Ok(value)
+    }
+
+    /// consumes the `[` or `{` that starts an array or object. errors end the parsing, so they
+    /// don't have to decrease the depth again
+    fn enter(&mut self) -> Result<(), Error> {
+        self.next();
+        if self.depth >= MAX_DEPTH {
+            return Err(self.error("nested too deeply"));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<Value, Error> {
+        self.whitespace();
+        Ok(match self.chars.peek() {
+            Some('"') => StringVal(Rc::from(self.string()?)),
+            Some('[') => {
+                self.enter()?;
+                let mut items = Vec::new();
+                self.whitespace();
+                if self.chars.peek() == Some(&']') {
+                    self.next();
+                } else {
+                    loop {
+                        items.push(self.value()?);
+                        self.whitespace();
+                        match self.next() {
+                            Some(',') => (),
+                            Some(']') => break,
+                            _ => return Err(self.error("expected `,` or `]`")),
+                        }
+                    }
+                }
+                self.depth -= 1;
+                List(Rc::new(RefCell::new(items)))
+            }
+            // there are no dictionaries, so objects are lists of (key, value) pairs
+            Some('{') => {
+                self.enter()?;
+                let mut items = Vec::new();
+                self.whitespace();
+                if self.chars.peek() == Some(&'}') {
+                    self.next();
+                } else {
+                    loop {
+                        self.whitespace();
+                        let key = StringVal(Rc::from(self.string()?));
+                        self.whitespace();
+                        if self.next() != Some(':') {
+                            return Err(self.error("expected `:`"));
+                        }
+                        let value = self.value()?;
+                        items.push(List(Rc::new(RefCell::new(vec![key, value]))));
+                        self.whitespace();
+                        match self.next() {
+                            Some(',') => (),
+                            Some('}') => break,
+                            _ => return Err(self.error("expected `,` or `}`")),
+                        }
+                    }
+                }
+                self.depth -= 1;
+                List(Rc::new(RefCell::new(items)))
+            }
+            Some('t') => self.keyword("true", Number(1f64))?,
+            Some('f') => self.keyword("false", Number(0f64))?,
+            Some('n') => self.keyword("null", Void)?,
+            Some('-' | '0'..='9') => {
+                let mut num = String::new();
+                while let Some(&chr @ ('-' | '+' | '.' | 'e' | 'E' | '0'..='9')) = self.chars.peek()
+                {
+                    num.push(chr);
+                    self.next();
+                }
+                Number(num.parse().map_err(|_| self.error("invalid number"))?)
+            }
+            Some(_) => {
+                self.next();
+                return Err(self.error("unexpected character"));
+            }
+            None => return Err(self.error("unexpected end of input")),
+        })
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        if self.next() != Some('"') {
+            return Err(self.error("expected a string"));
+        }
+        let mut string = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(string),
+                Some('\\') => string.push(match self.next() {
+                    Some(chr @ ('"' | '\\' | '/')) => chr,
+                    Some('b') => '\u{8}',
+                    Some('f') => '\u{c}',
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('u') => {
+                        let mut code = self.hex()?;
+                        // characters outside the BMP are written as two utf-16 surrogates
+                        if (0xd800..0xdc00).contains(&code) {
+                            if self.next() != Some('\\') || self.next() != Some('u') {
+                                return Err(self.error("expected a low surrogate"));
+                            }
+                            let low = self.hex()?;
+                            if !(0xdc00..0xe000).contains(&low) {
+                                return Err(self.error("expected a low surrogate"));
+                            }
+                            code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                        }
+                        char::from_u32(code).ok_or_else(|| self.error("invalid unicode escape"))?
+                    }
+                    _ => return Err(self.error("invalid escape")),
+                }),
+                Some(chr) => string.push(chr),
+                None => return Err(self.error("unclosed string")),
+            }
+        }
+    }
+
+    fn hex(&mut self) -> Result<u32, Error> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = (self.next().and_then(|x| x.to_digit(16)))
+                .ok_or_else(|| self.error("invalid unicode escape"))?;
+            code = code * 16 + digit;
+        }
+        Ok(code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(v: &Value) -> Value {
+        let mut json = String::new();
+        tojson(v, &mut json).unwrap();
+        fromjson(&json).unwrap()
+    }
+
+    fn list(items: Vec<Value>) -> Value {
+        List(Rc::new(RefCell::new(items)))
+    }
+
+    #[test]
+    fn values_survive_a_roundtrip() {
+        let values = [
+            Number(-1.5e10),
+            StringVal(Rc::from("quotes \" and \\ and \n and \u{1} and é")),
+            Void,
+            list(vec![]),
+            list(vec![
+                Number(1f64),
+                list(vec![StringVal(Rc::from("a")), Void]),
+            ]),
+        ];
+        for v in values {
+            assert_eq!(roundtrip(&v), v);
+        }
+    }
+
+    #[test]
+    fn deep_lists_are_an_error() {
+        let mut v = list(vec![]);
+        for _ in 0..MAX_DEPTH * 2 {
+            v = list(vec![v]);
+        }
+        assert!(matches!(
+            tojson(&v, &mut String::new()),
+            Err(Error::DepthError)
+        ));
+    }
+
+    #[test]
+    fn deep_nesting_is_an_error() {
+        let ok = "[".repeat(MAX_DEPTH) + &"]".repeat(MAX_DEPTH);
+        assert!(fromjson(&ok).is_ok());
+        let deep = "[".repeat(5000) + &"]".repeat(5000);
+        assert!(matches!(fromjson(&deep), Err(Error::JsonError(_, _))));
+    }
+}
@@ -1,65 +1,366 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
 mod builtins;
+mod json;
 mod parse;
 mod run;
 
 fn main() {
-    if let Some(path) = std::env::args().nth(1) {
-        let code = std::fs::read_to_string(path).expect("error while opening file");
-        let parsed = parse::parse(&code).expect("parsing error");
-        // parse::print_parsed(&parsed);
-        // println!("---");
-        run::execute(&parsed).unwrap_or_else(|x| {
-            eprintln!("{}", x);
-        });
-    } else {
-        repl();
+    // makes the random numbers the same every time, to reproduce a run
+    #[cfg(feature = "fastrand")]
+    if let Ok(seed) = std::env::var("VURL_SEED") {
+        match seed.parse::<u64>() {
+            Ok(seed) => fastrand::seed(seed),
+            Err(_) => eprintln!("warning: VURL_SEED must be a non-negative integer, ignoring it"),
+        }
+    }
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    match args.as_slice() {
+        [flag, path] if flag == "--ast" => {
+            let code = std::fs::read_to_string(path).expect("error while opening file");
+            parse::print_parsed(&parse_or_exit(&code));
+        }
+        [flag, code] if flag == "--eval" => run(code, None),
+        // missing the argument, or with too many
+        [flag, ..] if flag == "--ast" => usage("--ast <path>"),
+        [flag, ..] if flag == "--eval" => usage("--eval <code>"),
+        [path, ..] => {
+            let code = std::fs::read_to_string(path).expect("error while opening file");
+            run(&code, Some(path.as_ref()));
+        }
+        [] => repl(),
+    }
+}
+
+/// shows how a flag is used, when it was given without its argument
+fn usage(flag: &str) -> ! {
+    eprintln!("usage: vurlrs {}", flag);
+    std::process::exit(1);
+}
+
+/// runs a program, from the file at `path` if there is one, and exits with its status
+fn run(code: &str, path: Option<&Path>) {
+    let parsed = parse_or_exit(code);
+    let status = run::execute(Rc::from(parsed), path).unwrap_or_else(|x| {
+        eprintln!("{}", x);
+        show_snippet(code, &x);
+        1
+    });
+    // `exit` doesn't flush stdout, and `_printraw` could have left something there
+    let _ = std::io::Write::flush(&mut std::io::stdout());
+    std::process::exit(status);
+}
+
+/// parses the code, or shows the error and exits
+fn parse_or_exit(code: &str) -> Vec<Option<parse::Command>> {
+    parse::parse(code).unwrap_or_else(|e| {
+        show_parse_error(code, &e);
+        std::process::exit(1);
+    })
+}
+
+fn show_parse_error(code: &str, e: &parse::ParseError) {
+    eprintln!("parsing error: {}", e);
+    match e {
+        parse::ParseError::Lined(line, err) => {
+            eprintln!("{}", snippet(code, *line, Some(err.column)))
+        }
+        parse::ParseError::UnclosedBlock(line, _)
+        | parse::ParseError::UnexpectedEnd(line)
+        | parse::ParseError::UnexpectedCase(line)
+        | parse::ParseError::MismatchedEnd(line, ..) => {
+            eprintln!("{}", snippet(code, *line, None))
+        }
+    }
+}
+
+/// shows the line where a runtime error happened. `code` is the file that was run, but the line
+/// can be in a file it loaded
+fn show_snippet(code: &str, e: &run::RunError) {
+    match e.innermost() {
+        (None, line) => eprintln!("{}", snippet(code, line, None)),
+        (Some(path), line) => {
+            if let Ok(code) = std::fs::read_to_string(path.as_ref()) {
+                eprintln!("{}", snippet(&code, line, None))
+            }
+        }
     }
 }
 
 fn repl() {
     let stdin = std::io::stdin();
-    println!("welcome to vurlrs repl. do `quit` to quit.\nnote: you cannot use code blocks yet");
-    let lines = Vec::new();
+    println!("welcome to vurlrs repl. do `quit` to quit, and `load <path>` to run a file.\nnote: you cannot use code blocks yet");
+    let lines: Rc<[Option<parse::Command>]> = Rc::from([]);
     let mut globals = HashMap::new();
     let mut locals = HashMap::new();
     let mut functions = HashMap::new();
+    let mut repeats = HashMap::new();
+    let mut switches = HashMap::new();
+    let mut loaded = HashSet::new();
+    let mut memos = HashMap::new();
+    let mut calls = HashMap::new();
+    // the lines of the files run with `load`, to know which functions came from them
+    let mut files = HashMap::new();
     loop {
         print!(">>> ");
         let _ = std::io::Write::flush(&mut std::io::stdout());
         let mut buf = String::new();
         stdin.read_line(&mut buf).expect("error reading from stdin");
-        let line = buf.trim();
-        if line.starts_with('[') && line.ends_with(']') && !line.contains(' ') {
-            buf = String::from("print ") + line;
+        let mut parsed = parse::parse_line(&buf);
+        // a line that starts with a variable or a parenthesis is not a command, so its values
+        // are printed instead, like `print` would
+        if let Err(parse::ParseErrorLine {
+            kind: parse::ParseErrorKind::NameIsNotString,
+            ..
+        }) = parsed
+        {
+            parsed = parse::parse_line(&(String::from("print ") + buf.trim_start())).map_err(|e| {
+                // the columns shouldn't count the `print `
+                let indent = buf.len() - buf.trim_start().len();
+                parse::ParseErrorLine {
+                    column: (e.column + indent).saturating_sub("print ".len()),
+                    ..e
+                }
+            });
         }
-        match parse::parse_line(&buf) {
+        match parsed {
             Err(x) => {
                 eprintln!("parsing error: {}", x);
                 continue;
             }
             Ok(None) => (),
             Ok(Some(cmd)) => {
-                if cmd.name == "quit" {
+                if &*cmd.name == "quit" {
                     println!("bye");
                     return;
                 }
-                // right now this is a bit useless. when we actually handle function definitions it
-                // will be necessary to keep the lines in `lines`
+                // every command runs by itself, so `lines` is empty. functions defined in files
+                // keep the lines of their file
                 let mut state = run::State {
                     globals: &mut globals,
                     locals: &mut locals,
                     functions: &mut functions,
+                    repeats: &mut repeats,
+                    switches: &mut switches,
+                    loaded: &mut loaded,
+                    memos: &mut memos,
+                    calls: &mut calls,
                     lineno: lines.len(),
-                    lines: &lines,
+                    lines: Rc::clone(&lines),
+                    file: None,
                 };
-                match run::evaluate(&mut state, &parse::Expr::Command(cmd.to_owned())) {
-                    Err(e) => eprintln!("error: {}", e),
-                    Ok(run::Value::String(x)) if x.is_empty() => (),
-                    Ok(val) => println!("{}", val),
+                match (cmd.name.as_ref(), cmd.args.as_slice()) {
+                    ("load", [parse::Expr::Literal(path)]) => {
+                        if let Some((code, lines)) = read(path) {
+                            load(&mut state, path, &code, lines, &mut files);
+                        }
+                    }
+                    ("save", [parse::Expr::Literal(path)]) => {
+                        if let Err(e) = std::fs::write(path.as_ref(), save(&state)) {
+                            eprintln!("io error: {}", e);
+                        }
+                    }
+                    // the session is only thrown away once the file is known to be good
+                    ("restore", [parse::Expr::Literal(path)]) => {
+                        if let Some((code, lines)) = read(path) {
+                            state.globals.clear();
+                            state.functions.clear();
+                            state.memos.clear();
+                            state.calls.clear();
+                            state.loaded.clear();
+                            files.clear();
+                            load(&mut state, path, &code, lines, &mut files);
+                        }
+                    }
+                    ("load" | "save" | "restore", _) => eprintln!("usage: {} <path>", cmd.name),
+                    _ => match run::evaluate_command(&mut state, &cmd) {
+                        Err(e) => match e.exit_code() {
+                            Some(code) => std::process::exit(code),
+                            None => eprintln!("{}", e),
+                        },
+                        Ok(run::Value::Void) => (),
+                        Ok(val) => println!("{}", val),
+                    },
                 }
             }
         }
     }
 }
+
+/// reads and parses a file for `load` or `restore`, or shows why it couldn't
+fn read(path: &str) -> Option<(String, Rc<[Option<parse::Command>]>)> {
+    let code = match std::fs::read_to_string(path) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("io error: {}", e);
+            return None;
+        }
+    };
+    match parse::parse(&code) {
+        Ok(lines) => Some((code, Rc::from(lines))),
+        Err(e) => {
+            show_parse_error(&code, &e);
+            None
+        }
+    }
+}
+
+/// runs a file in the repl. if it was loaded before, its old functions are replaced
+fn load(
+    state: &mut run::State,
+    path: &str,
+    code: &str,
+    lines: Rc<[Option<parse::Command>]>,
+    files: &mut HashMap<PathBuf, Rc<[Option<parse::Command>]>>,
+) {
+    let canonical = Path::new(path)
+        .canonicalize()
+        .unwrap_or_else(|_| PathBuf::from(path));
+    if let Some(old) = files.insert(canonical.clone(), Rc::clone(&lines)) {
+        state.functions.retain(|_, f| !Rc::ptr_eq(&f.lines, &old));
+        state
+            .memos
+            .retain(|name, _| state.functions.contains_key(name));
+    }
+    state.loaded.insert(canonical);
+    let mut state = run::State {
+        globals: state.globals,
+        locals: &mut HashMap::new(),
+        functions: state.functions,
+        repeats: &mut HashMap::new(),
+        switches: &mut HashMap::new(),
+        loaded: state.loaded,
+        memos: state.memos,
+        calls: state.calls,
+        lineno: 0,
+        lines,
+        file: Some(Rc::from(path)),
+    };
+    if let Err(e) = run::execute_with_state(&mut state) {
+        if let Some(code) = e.exit_code() {
+            std::process::exit(code);
+        }
+        eprintln!("{}", e);
+        show_snippet(code, &e);
+    }
+}
+
+/// writes the functions and global variables as a program that defines them again. `restore`
+/// runs it with `load`
+fn save(state: &run::State) -> String {
+    let mut code = String::new();
+    let mut functions = state.functions.iter().collect::<Vec<_>>();
+    functions.sort_unstable_by_key(|(name, _)| *name);
+    for (_, function) in functions {
+        // the line before the body is the `define` or `_cmd`, which points to its `end`
+        let start = function.lineno - 1;
+        let Some(parse::Expr::Lineptr(end)) =
+            (function.lines[start].as_ref()).and_then(|cmd| cmd.args.last())
+        else {
+            continue;
+        };
+        let mut depth = 0;
+        for line in &function.lines[start..=*end] {
+            let Some(cmd) = line else {
+                writeln!(code).unwrap();
+                continue;
+            };
+            let name = cmd.name.split(' ').next().unwrap();
+            if name == "end" {
+                depth -= 1;
+            }
+            // `case` and `default` go at the same level as their `switch`
+            let indent = match name {
+                "case" | "default" => depth - 1,
+                _ => depth,
+            };
+            writeln!(code, "{}{}", "    ".repeat(indent), parse::unparse(cmd)).unwrap();
+            if parse::BLOCKS.contains(&name) {
+                depth += 1;
+            }
+        }
+    }
+    let mut memos = state.memos.keys().collect::<Vec<_>>();
+    memos.sort_unstable();
+    for name in memos {
+        let name = name.strip_prefix("call ").unwrap_or(name);
+        writeln!(
+            code,
+            "_memoize {}",
+            source(&run::Value::String(Rc::from(name)))
+        )
+        .unwrap();
+    }
+    let mut globals = state.globals.iter().collect::<Vec<_>>();
+    globals.sort_unstable_by_key(|(name, _)| *name);
+    for (name, value) in globals {
+        let name = source(&run::Value::String(Rc::clone(name)));
+        writeln!(code, "set {} {}", name, source(value)).unwrap();
+    }
+    code
+}
+
+/// writes a value as code that evaluates to it. lists are copied, so if the same list was in two
+/// variables, there will be two lists
+fn source(value: &run::Value) -> String {
+    match value {
+        run::Value::String(s) => parse::quote(s).unwrap_or_else(|| {
+            let bytes = s.bytes().map(|b| b.to_string()).collect::<Vec<_>>();
+            format!("(_frombytes (list {}))", bytes.join(" "))
+        }),
+        run::Value::Number(n) => n.to_string(),
+        run::Value::List(l) => {
+            let mut code = String::from("(list");
+            for item in l.borrow().iter() {
+                code += " ";
+                code += &source(item);
+            }
+            code + ")"
+        }
+        run::Value::Func(func) => match func.as_ref() {
+            run::Callable::Named(name) => {
+                format!("(_fn {})", source(&run::Value::String(Rc::clone(name))))
+            }
+            run::Callable::Compose(f, g) => format!("(_compose {} {})", source(f), source(g)),
+            run::Callable::Partial(f, args) => {
+                let mut code = format!("(_partial {}", source(f));
+                for arg in args.iter() {
+                    code += " ";
+                    code += &source(arg);
+                }
+                code + ")"
+            }
+        },
+        run::Value::Void => String::from("(_void)"),
+        run::Value::Lineptr(_) => unreachable!(),
+    }
+}
+
+/// shows a line of the source code with a caret under a column, like rustc does. without a column
+/// the whole line is underlined
+fn snippet(code: &str, lineno: usize, column: Option<usize>) -> String {
+    let line = code.split('\n').nth(lineno).unwrap_or_default().trim_end();
+    let prefix = format!("{} | ", lineno + 1);
+    let (start, len) = match column {
+        Some(column) => (column - 1, 1),
+        None => {
+            let indent = line.chars().take_while(|c| c.is_whitespace()).count();
+            (indent, line.chars().count() - indent)
+        }
+    };
+    // keep the tabs, so the caret lines up with the code
+    let padding = (line.chars().take(start))
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect::<String>();
+    format!(
+        "{}{}\n{}{}{}",
+        prefix,
+        line,
+        " ".repeat(prefix.len()),
+        padding,
+        "^".repeat(len.max(1))
+    )
+}
@@ -1,6 +1,6 @@
-use std::{fmt, iter, str};
+use std::{fmt, iter, rc::Rc, str};
 
-#[allow(dead_code)]
+/// shows the parsed lines, one per line, with `~` for empty ones. used by `--ast`
 pub fn print_parsed(parsed: &[Option<Command>]) {
     for line in parsed {
         if let Some(cmd) = line {
@@ -14,15 +14,18 @@ pub fn print_parsed(parsed: &[Option<Command>]) {
 #[derive(Clone, PartialEq, Debug)]
 pub enum Expr {
     Command(Command),
-    Literal(String),
+    // stored as an `Rc` so evaluating it doesn't need to allocate a new string every time
+    Literal(Rc<str>),
     Number(f64),
     Variable(String),
+    // a quoted string with variables in it. its parts are literals and variables
+    Interpolation(Vec<Expr>),
     Lineptr(usize),
 }
 
 #[derive(Clone, PartialEq, Debug)]
 pub struct Command {
-    pub name: String,
+    pub name: Rc<str>,
     pub args: Vec<Expr>,
 }
 
@@ -33,6 +36,19 @@ impl fmt::Display for Expr {
             Self::Literal(s) => write!(f, "\"{}\"", s.replace('"', r#"\""#)),
             Self::Number(n) => write!(f, "{}", n),
             Self::Variable(s) => write!(f, "[{}]", s),
+            Self::Interpolation(parts) => {
+                write!(f, "\"")?;
+                for part in parts {
+                    match part {
+                        Self::Variable(s) => write!(f, "${{{}}}", s)?,
+                        Self::Literal(s) => {
+                            write!(f, "{}", s.replace('$', "$$").replace('"', r#"\""#))?
+                        }
+                        _ => unreachable!(),
+                    }
+                }
+                write!(f, "\"")
+            }
             Self::Lineptr(s) => write!(f, "(line {})", s),
         }
     }
@@ -56,12 +72,22 @@ impl fmt::Display for Command {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ParseError {
     Lined(usize, ParseErrorLine),
-    UnclosedBlock,
-    UnexpectedEnd,
+    // the line where the block starts, and its name
+    UnclosedBlock(usize, Rc<str>),
+    UnexpectedEnd(usize),
+    UnexpectedCase(usize),
+    // the line of the `end`, the block it says it closes, and the one it actually closes
+    MismatchedEnd(usize, Rc<str>, Rc<str>),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub enum ParseErrorLine {
+pub struct ParseErrorLine {
+    pub column: usize,
+    pub kind: ParseErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseErrorKind {
     StringEOL,
     NameIsNotString,
     UnclosedParen,
@@ -72,15 +98,35 @@ pub enum ParseErrorLine {
 impl fmt::Display for ParseError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::Lined(line, error) => write!(f, "error at line {}: {}", line, error),
-            Self::UnclosedBlock => write!(f, "unclosed block"),
-            Self::UnexpectedEnd => write!(f, "unexpected `end`"),
+            Self::Lined(line, error) => write!(f, "line {}, {}", line + 1, error),
+            Self::UnclosedBlock(line, name) => {
+                write!(f, "line {}: `{}` block is never closed", line + 1, name)
+            }
+            Self::UnexpectedEnd(line) => write!(f, "line {}: unexpected `end`", line + 1),
+            Self::MismatchedEnd(line, closes, block) => write!(
+                f,
+                "line {}: `end {}` closes a block started with `{}`",
+                line + 1,
+                closes,
+                block
+            ),
+            Self::UnexpectedCase(line) => write!(
+                f,
+                "line {}: `case` or `default` outside of a `switch`",
+                line + 1
+            ),
         }
     }
 }
 impl std::error::Error for ParseError {}
 
 impl fmt::Display for ParseErrorLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "column {}: {}", self.column, self.kind)
+    }
+}
+
+impl fmt::Display for ParseErrorKind {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let str = match self {
             Self::StringEOL => "quoted strings cannot span multiple lines",
@@ -93,20 +139,69 @@ impl fmt::Display for ParseErrorLine {
     }
 }
 
+/// commands that start a block, which is closed by `end`
+pub const BLOCKS: &[&str] = &["if", "while", "repeat", "switch", "define", "_cmd"];
+
+/// commands that are handled by the parser, and can't be used like normal commands
+pub fn is_block_keyword(name: &str) -> bool {
+    BLOCKS.contains(&name) || matches!(name, "end" | "case" | "default")
+}
+
 pub fn parse(code: &str) -> Result<Vec<Option<Command>>, ParseError> {
     let mut stack = Vec::new();
     let mut commands = Vec::<Option<Command>>::new();
     for (lineno, line) in code.split('\n').enumerate() {
         if let Some(mut cmd) = parse_line(line).map_err(|e| ParseError::Lined(lineno, e))? {
-            match cmd.name.as_str() {
-                "if" | "while" | "define" | "_cmd" => stack.push(lineno),
+            match cmd.name.as_ref() {
+                name if BLOCKS.contains(&name) => stack.push(lineno),
+                // the `switch` stays on the stack, with the last `case` on top of it. each case
+                // points to the switch, and to the next case (or the end) to jump there if it
+                // doesn't match. the switch points to the first case
+                "case" | "default" => {
+                    let prevno = stack.pop().ok_or(ParseError::UnexpectedCase(lineno))?;
+                    let prev = commands[prevno].as_mut().unwrap();
+                    match prev.name.as_ref() {
+                        "switch" => stack.push(prevno),
+                        "case" | "default" => (),
+                        _ => return Err(ParseError::UnexpectedCase(lineno)),
+                    }
+                    prev.args.push(Expr::Lineptr(lineno));
+                    cmd.args.push(Expr::Lineptr(*stack.last().unwrap()));
+                    stack.push(lineno);
+                }
                 "end" => {
-                    let startno = stack.pop().ok_or(ParseError::UnexpectedEnd)?;
+                    let mut startno = stack.pop().ok_or(ParseError::UnexpectedEnd(lineno))?;
+                    // `end while`, `end if`... say which block they close, so mistakes are caught
+                    if let [Expr::Literal(closes)] = cmd.args.as_slice() {
+                        if BLOCKS.contains(&closes.as_ref()) {
+                            let block = match commands[startno].as_ref().unwrap().name.as_ref() {
+                                "case" | "default" => "switch",
+                                name => name,
+                            };
+                            if closes.as_ref() != block {
+                                return Err(ParseError::MismatchedEnd(
+                                    lineno,
+                                    Rc::clone(closes),
+                                    Rc::from(block),
+                                ));
+                            }
+                            cmd.args.clear();
+                        }
+                    }
+                    if let "case" | "default" = commands[startno].as_ref().unwrap().name.as_ref() {
+                        let caseline = commands[startno].as_mut().unwrap();
+                        caseline.args.push(Expr::Lineptr(lineno));
+                        startno = stack.pop().unwrap();
+                        cmd.args.push(Expr::Lineptr(startno));
+                        cmd.name = Rc::from("end switch");
+                        commands.push(Some(cmd));
+                        continue;
+                    }
                     let startline = commands[startno].as_mut().unwrap();
                     startline.args.push(Expr::Lineptr(lineno));
 
                     cmd.args.push(Expr::Lineptr(startno));
-                    cmd.name = cmd.name + " " + &startline.name;
+                    cmd.name = Rc::from(format!("{} {}", cmd.name, startline.name));
                 }
                 _ => (),
             }
@@ -115,48 +210,76 @@ pub fn parse(code: &str) -> Result<Vec<Option<Command>>, ParseError> {
             commands.push(None);
         }
     }
-    if !stack.is_empty() {
-        return Err(ParseError::UnclosedBlock);
+    // the innermost block is the one that's reported
+    if let Some(&startno) = stack.last() {
+        let name = &commands[startno].as_ref().unwrap().name;
+        return Err(ParseError::UnclosedBlock(startno, Rc::clone(name)));
     }
     Ok(commands)
 }
 
 pub fn parse_line(line: &str) -> Result<Option<Command>, ParseErrorLine> {
-    let line = line.trim();
-    if !line.is_empty() && !line.starts_with('#') {
-        let cmd = parse_command(&mut line.trim().chars().peekable(), true)?;
+    let line = line.trim_end();
+    let trimmed = line.trim_start();
+    if !trimmed.is_empty() && !trimmed.starts_with('#') {
+        let cmd =
+            parse_command(&mut trimmed.chars().peekable(), true).map_err(|(left, kind)| {
+                ParseErrorLine {
+                    column: line.chars().count() - left + 1,
+                    kind,
+                }
+            })?;
         Ok(Some(cmd))
     } else {
         Ok(None)
     }
 }
 
+/// how many characters are left to parse. this is used to find the column of errors, without
+/// having to keep count of it while parsing. it's slow, so it should only be called on errors:
+/// to remember a position, keep a clone of `chars`, which is cheap
+fn left(chars: &iter::Peekable<str::Chars>) -> usize {
+    chars.clone().count()
+}
+
 fn parse_command(
     chars: &mut iter::Peekable<str::Chars>,
     is_top_level: bool,
-) -> Result<Command, ParseErrorLine> {
+) -> Result<Command, (usize, ParseErrorKind)> {
+    let start = chars.clone();
     let mut args: Vec<Expr> = vec![];
     loop {
         match chars.next() {
-            Some('(') => args.push(Expr::Command(parse_command(chars, false)?)),
+            Some('(') => {
+                let paren = chars.clone();
+                let cmd = parse_command(chars, false).map_err(|(at, kind)| match kind {
+                    // point to the parenthesis, instead of the end of the line
+                    ParseErrorKind::UnclosedParen => (left(&paren) + 1, kind),
+                    _ => (at, kind),
+                })?;
+                args.push(Expr::Command(cmd))
+            }
             Some('"') => {
+                let quote = chars.clone();
                 let mut s = String::new();
                 loop {
                     match chars.next() {
                         Some('"') if matches!(chars.peek(), Some(')' | ' ') | None) => break,
                         Some(chr) => s.push(chr),
-                        None => return Err(ParseErrorLine::StringEOL),
+                        None => return Err((left(&quote) + 1, ParseErrorKind::StringEOL)),
                     }
                 }
-                args.push(Expr::Literal(s))
+                args.push(interpolate(s))
             }
             Some(' ') => (),
 
-            Some(')') if is_top_level => return Err(ParseErrorLine::UnexpectedParen),
+            Some(')') if is_top_level => {
+                return Err((left(chars) + 1, ParseErrorKind::UnexpectedParen))
+            }
             Some(')') => break,
 
             None if is_top_level => break,
-            None => return Err(ParseErrorLine::UnclosedParen),
+            None => return Err((0, ParseErrorKind::UnclosedParen)),
 
             Some(fst) => {
                 let mut s = String::from(fst);
@@ -174,20 +297,240 @@ fn parse_command(
                 }
                 args.push(if s.starts_with('[') && s.ends_with(']') {
                     Expr::Variable(s[1..s.len() - 1].to_owned())
-                } else if let Ok(x) = s.parse::<f64>() {
+                } else if let Some(x) = parse_number(&s) {
                     Expr::Number(x)
                 } else {
-                    Expr::Literal(s)
+                    Expr::Literal(Rc::from(s))
                 })
             }
         }
     }
-    if let Expr::Literal(name) = &args.get(0).ok_or(ParseErrorLine::EmptyCommand)? {
+    if let Expr::Literal(name) = &args
+        .first()
+        .ok_or_else(|| (left(&start), ParseErrorKind::EmptyCommand))?
+    {
         Ok(Command {
-            name: name.to_owned(),
+            name: Rc::clone(name),
             args: args[1..].to_vec(),
         })
     } else {
-        Err(ParseErrorLine::NameIsNotString)
+        Err((left(&start), ParseErrorKind::NameIsNotString))
+    }
+}
+
+/// writes `s` as a quoted string that parses back to it, if that's possible. a quote followed by
+/// a space or a parenthesis would end the string, and newlines can't be in one at all
+pub fn quote(s: &str) -> Option<String> {
+    let mut chars = s.chars().peekable();
+    let mut quoted = String::from('"');
+    while let Some(chr) = chars.next() {
+        match (chr, chars.peek()) {
+            ('"', Some(' ' | ')')) | ('\n', _) => return None,
+            ('$', _) => quoted.push_str("$$"),
+            (chr, _) => quoted.push(chr),
+        }
+    }
+    quoted.push('"');
+    Some(quoted)
+}
+
+/// writes a command back as code. the line numbers added by the parser are left out, and so is
+/// the name of the block after an `end`
+pub fn unparse(cmd: &Command) -> String {
+    let mut code = match cmd.name.split_once(' ') {
+        Some(("end", _)) => String::from("end"),
+        _ => unparse_literal(&cmd.name),
+    };
+    for arg in &cmd.args {
+        match arg {
+            Expr::Lineptr(_) => (),
+            Expr::Command(cmd) => code += &format!(" ({})", unparse(cmd)),
+            Expr::Literal(s) => code += &format!(" {}", unparse_literal(s)),
+            Expr::Number(n) => code += &format!(" {}", n),
+            Expr::Variable(s) => code += &format!(" [{}]", s),
+            Expr::Interpolation(parts) => {
+                code += " \"";
+                for part in parts {
+                    match part {
+                        Expr::Variable(s) => code += &format!("${{{}}}", s),
+                        Expr::Literal(s) => code += &s.replace('$', "$$"),
+                        _ => unreachable!(),
+                    }
+                }
+                code += "\"";
+            }
+        }
+    }
+    code
+}
+
+/// literals are only quoted when they would be parsed as something else without the quotes
+fn unparse_literal(s: &str) -> String {
+    let needs_quotes = s.is_empty()
+        || s.starts_with(['"', '#'])
+        || s.contains([' ', '(', ')'])
+        || (s.starts_with('[') && s.ends_with(']'))
+        || parse_number(s).is_some();
+    if !needs_quotes {
+        return s.to_string();
+    }
+    // this literal was in the code, so it could be quoted there
+    quote(s).unwrap_or_else(|| s.to_string())
+}
+
+/// parses an unquoted number. besides decimal numbers, it takes integers in hexadecimal
+/// (`0xff`), binary (`0b1010`) and octal (`0o17`), and underscores between the digits
+fn parse_number(s: &str) -> Option<f64> {
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, s),
+    };
+    // underscores can separate digits, like `1_000_000`, but a number can't start or end with one
+    if digits.contains('_') {
+        if !digits.starts_with(|c: char| c.is_ascii_digit()) || digits.ends_with('_') {
+            return None;
+        }
+        return parse_number(&s.replace('_', ""));
+    }
+    let radix = match digits.get(..2) {
+        Some("0x") => 16,
+        Some("0b") => 2,
+        Some("0o") => 8,
+        _ => return s.parse().ok(),
+    };
+    // `from_str_radix` would also take a sign after the prefix
+    if digits[2..].starts_with('+') {
+        return None;
+    }
+    let n = u128::from_str_radix(&digits[2..], radix).ok()? as f64;
+    Some(if negative { -n } else { n })
+}
+
+/// splits a quoted string into literals and the variables in it, written like `$name` or
+/// `${name}`. `$$` is a literal `$`, and so is a `$` not followed by a name, or a `${` that isn't
+/// closed
+fn interpolate(s: String) -> Expr {
+    if !s.contains('$') {
+        return Expr::Literal(Rc::from(s));
+    }
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut chars = s.chars().peekable();
+    while let Some(chr) = chars.next() {
+        if chr != '$' {
+            literal.push(chr);
+            continue;
+        }
+        let mut name = String::new();
+        match chars.peek() {
+            Some('$') => {
+                chars.next();
+                literal.push('$');
+                continue;
+            }
+            Some('{') => {
+                chars.next();
+                let mut closed = false;
+                for chr in chars.by_ref() {
+                    if chr == '}' {
+                        closed = true;
+                        break;
+                    }
+                    name.push(chr);
+                }
+                // without the `}` it's probably not meant to be a variable
+                if !closed {
+                    literal.push_str("${");
+                    literal.push_str(&name);
+                    continue;
+                }
+            }
+            _ => {
+                // locals start with a dot. a dot anywhere else is probably punctuation
+                if chars.peek() == Some(&'.') {
+                    name.push('.');
+                    chars.next();
+                }
+                while let Some(&chr) = chars.peek().filter(|c| c.is_alphanumeric() || **c == '_') {
+                    name.push(chr);
+                    chars.next();
+                }
+            }
+        }
+        if name.is_empty() || name == "." {
+            literal.push('$');
+            literal.push_str(&name);
+            continue;
+        }
+        if !literal.is_empty() {
+            parts.push(Expr::Literal(Rc::from(std::mem::take(&mut literal))));
+        }
+        parts.push(Expr::Variable(name));
+    }
+    if !literal.is_empty() {
+        parts.push(Expr::Literal(Rc::from(literal)));
+    }
+    Expr::Interpolation(parts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the line pointers of every line, which is what the parser adds to blocks
+    fn lineptrs(code: &str) -> Vec<Vec<usize>> {
+        (parse(code).unwrap().iter())
+            .map(|line| match line {
+                Some(cmd) => (cmd.args.iter())
+                    .filter_map(|arg| match arg {
+                        Expr::Lineptr(l) => Some(*l),
+                        _ => None,
+                    })
+                    .collect(),
+                None => Vec::new(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn switch_links_its_cases() {
+        let code = "switch [x]\ncase 1\n    print a\ncase 2\ndefault\n    print b\nend";
+        assert_eq!(
+            lineptrs(code),
+            [
+                vec![1],
+                vec![0, 3],
+                vec![],
+                vec![0, 4],
+                vec![0, 6],
+                vec![],
+                vec![0]
+            ]
+        );
+        let lines = parse(code).unwrap();
+        assert_eq!(&*lines[6].as_ref().unwrap().name, "end switch");
+    }
+
+    #[test]
+    fn unclosed_interpolation_is_literal() {
+        let cmd = parse_line("print \"a ${name\" \"${x}!\"").unwrap().unwrap();
+        let literal = |s: &str| Expr::Literal(Rc::from(s));
+        assert_eq!(cmd.args[0], Expr::Interpolation(vec![literal("a ${name")]));
+        assert_eq!(
+            cmd.args[1],
+            Expr::Interpolation(vec![Expr::Variable(String::from("x")), literal("!")])
+        );
+    }
+
+    #[test]
+    fn case_outside_of_switch() {
+        assert!(matches!(
+            parse("if 1\ncase 2\nend"),
+            Err(ParseError::UnexpectedCase(1))
+        ));
+        assert!(matches!(
+            parse("case 2"),
+            Err(ParseError::UnexpectedCase(0))
+        ));
     }
 }
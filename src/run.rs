@@ -1,35 +1,106 @@
 use crate::{builtins, parse};
 use parse::{Command, Expr};
-use std::{cell::RefCell, collections::HashMap, error::Error, fmt, rc::Rc};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Write};
+use std::{cell::RefCell, error::Error, path::Path, path::PathBuf, rc::Rc};
 
 #[derive(PartialEq, Debug)]
 pub struct State<'a> {
     pub globals: &'a mut HashMap<Rc<str>, Value>,
     pub locals: &'a mut HashMap<Rc<str>, Value>,
     pub functions: &'a mut HashMap<Rc<str>, Function>,
+    // iterations left for each `repeat` block that is currently running, by line number
+    pub repeats: &'a mut HashMap<usize, usize>,
+    // the values of the `switch` blocks that haven't found a matching `case` yet, by line number
+    pub switches: &'a mut HashMap<usize, Value>,
+    // the files that have been run already, so `_load` doesn't run them again
+    pub loaded: &'a mut HashSet<PathBuf>,
+    // the results of the functions passed to `_memoize`, by name and then by `memo_key` of the
+    // arguments
+    pub memos: &'a mut HashMap<Rc<str>, HashMap<String, Value>>,
+    // the keys in `functions` of the functions made with `define`, which are "call " and the
+    // name, so `call` doesn't have to make the key every time. it can have functions that don't
+    // exist anymore
+    pub calls: &'a mut HashMap<Rc<str>, Rc<str>>,
     pub lineno: usize,
-    pub lines: &'a [Option<Command>],
+    pub lines: Rc<[Option<Command>]>,
+    // the file `lines` came from, or `None` if it's the one that the program started with
+    pub file: Option<Rc<str>>,
 }
 
 #[derive(Clone, PartialEq, Debug)]
 pub struct Function {
+    // the lines of the file it was defined in, which is not the main one if it was `_load`ed
+    pub lines: Rc<[Option<Command>]>,
+    // the file of `lines`, like in `State`. errors inside of the function say which file it was
+    pub file: Option<Rc<str>>,
     pub lineno: usize,
     pub arguments: Option<Rc<[Rc<str>]>>,
+    // how many of the arguments must be passed. the rest are void if missing
+    pub required: usize,
+    // the argument that takes a list of all the arguments after the others, if any
+    pub rest: Option<Rc<str>>,
 }
 
-#[derive(Clone, PartialEq, Debug)]
+#[derive(Clone, PartialEq, Debug, Default)]
 pub enum Value {
     String(Rc<str>),
     List(Rc<RefCell<Vec<Value>>>),
     Number(f64),
+    // a value that can be called like a command, made with `_fn`, `_compose` or `_partial`
+    Func(Rc<Callable>),
+    // no value. returned by commands like `set` and `print`, and given to optional arguments
+    // that weren't passed. it is shown as nothing, like an empty string
+    #[default]
+    Void,
     // not a real value. used in `end` to point to the start of the block, and in
-    // `while|if|define|_cmd` to point to the end
+    // `while|if|repeat|define|_cmd` to point to the end. `switch|case|default` use them too
     Lineptr(usize),
 }
 
-impl Default for Value {
-    fn default() -> Self {
-        Self::String(Rc::from(""))
+#[derive(Clone, PartialEq, Debug)]
+pub enum Callable {
+    // a command. it displays as its name, so it also works where names do
+    Named(Rc<str>),
+    // calls the second one with the arguments, and then the first one with its result
+    Compose(Value, Value),
+    // calls the command with these arguments, followed by the ones it gets
+    Partial(Value, Rc<[Value]>),
+}
+
+impl Callable {
+    // the values inside can be lists, so this keeps counting like `Value::fmt_nested`
+    fn fmt_nested(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        depth: usize,
+        left: &mut usize,
+    ) -> fmt::Result {
+        match self {
+            Self::Named(name) => write!(f, "{}", name),
+            Self::Compose(first, second) => {
+                write!(f, "_compose(")?;
+                first.fmt_nested(f, depth + 1, left)?;
+                write!(f, " ")?;
+                second.fmt_nested(f, depth + 1, left)?;
+                write!(f, ")")
+            }
+            Self::Partial(func, args) => {
+                write!(f, "_partial(")?;
+                func.fmt_nested(f, depth + 1, left)?;
+                for arg in args.iter() {
+                    write!(f, " ")?;
+                    arg.fmt_nested(f, depth + 1, left)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+impl fmt::Display for Callable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_nested(f, 0, &mut { MAX_LISTS })
     }
 }
 
@@ -39,113 +110,272 @@ pub struct RunError {
     function: Rc<str>,
     inner: RunErrorKind,
 }
+/// shows the error like a stack trace: the message, and then the line and command of every
+/// function call it went through, from the innermost one
 impl fmt::Display for RunError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "error (line {}, command {}):\n{}",
-            self.line + 1,
-            self.function,
-            self.inner
-        )
+        let mut frames = Vec::new();
+        let mut error = self;
+        let mut file = None;
+        loop {
+            frames.push((error.line, &error.function, file));
+            match &error.inner {
+                RunErrorKind::Wrap(e) => error = e,
+                RunErrorKind::InFile(path, e) => {
+                    file = path.as_ref();
+                    error = e;
+                }
+                _ => break,
+            }
+        }
+        write!(f, "error: {}", error.inner)?;
+        for (line, function, file) in frames.into_iter().rev() {
+            write!(f, "\n    at line {}", line + 1)?;
+            if let Some(file) = file {
+                write!(f, " of {}", file)?;
+            }
+            write!(f, ": {}", function)?;
+        }
+        Ok(())
     }
 }
 impl Error for RunError {}
 
+impl RunError {
+    /// the line where the error happened, inside of all the function calls, and the file it is
+    /// in. `None` is the file that was being run, where the outermost line is
+    pub fn innermost(&self) -> (Option<&Rc<str>>, usize) {
+        let (mut error, mut file) = (self, None);
+        loop {
+            match &error.inner {
+                RunErrorKind::Wrap(e) => error = e,
+                RunErrorKind::InFile(path, e) => {
+                    file = path.as_ref();
+                    error = e;
+                }
+                _ => return (file, error.line),
+            }
+        }
+    }
+
+    /// the status passed to `_exit`, if that is what stopped the program
+    pub fn exit_code(&self) -> Option<i32> {
+        match &self.inner {
+            RunErrorKind::Wrap(e) | RunErrorKind::InFile(_, e) => e.exit_code(),
+            RunErrorKind::Exit(code) => Some(*code),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum RunErrorKind {
     Wrap(Box<RunError>), // wraps another error. this means good backtraces
-    Return(Value),       // returning is an error, obviously
-    IsNotBuiltIn,        // internal, used by execute_commands, should not be propagated
+    // same, for errors in another file. `None` is the main file
+    InFile(Option<Rc<str>>, Box<RunError>),
+    Return(Value), // returning is an error, obviously
+    Exit(i32),     // so is exiting. it goes through all the functions
+    IsNotBuiltIn,  // internal, used by execute_commands, should not be propagated
     ValueError(usize),
+    ValueRangeError(usize, usize),
+    ValueAtLeastError(usize),
     NotDefined,
     MustBeTopLevel,
     UserError(Rc<str>),
     NameError(Rc<str>),
     FuncDefined(Rc<str>),
+    ReservedName(Rc<str>),
+    OptionalOrder(Rc<str>),
+    RestOrder(Rc<str>),
     IsNotNumber(Value),
     IsNotList(Value),
     IOError(std::io::Error),
+    ParseError(parse::ParseError),
     ZeroIndex,
-    IndexError(usize, usize),
+    IndexError(f64, usize),
+    GridIndexError(&'static str, f64, usize),
     PopError,
+    EmptyError,
+    LengthError(usize, usize),
+    SizeError(f64),
+    TooLongError,
+    DepthError,
+    #[allow(dead_code)]
+    SampleError(usize, usize),
+    #[allow(dead_code)]
+    RandomError(i64, i64),
+    CycleError,
     OrdError(Rc<str>),
     ChrError(u32),
+    ByteError(f64),
+    Utf8Error(std::string::FromUtf8Error),
+    ClampError(f64, f64),
+    JsonError(usize, &'static str),
+    FormatError(&'static str),
+    #[cfg(feature = "regex")]
+    RegexError(regex::Error),
     #[allow(dead_code)]
     RandUnavailable,
+    #[allow(dead_code)]
+    RegexUnavailable,
 }
 
 impl fmt::Display for RunErrorKind {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Wrap(e) => write!(f, "{}", e),
+            Self::InFile(Some(path), e) => write!(f, "in file {}:\n{}", path, e),
+            Self::InFile(None, e) => write!(f, "in the main file:\n{}", e),
             Self::Return(v) => write!(f, "value {} returned outside function", v),
+            Self::Exit(code) => write!(f, "exited with status {}", code),
             Self::ValueError(num) => write!(
                 f,
                 "expected {} argument{}",
                 num,
                 if *num == 1 { "" } else { "s" }
             ),
+            Self::ValueRangeError(min, max) => {
+                write!(f, "expected between {} and {} arguments", min, max)
+            }
+            Self::ValueAtLeastError(num) => write!(
+                f,
+                "expected at least {} argument{}",
+                num,
+                if *num == 1 { "" } else { "s" }
+            ),
             Self::IsNotBuiltIn => panic!("NotBuiltIn should not be propagated"),
             Self::NotDefined => write!(f, "command not defined"),
             Self::MustBeTopLevel => write!(f, "command must be used in top level"),
             Self::UserError(e) => write!(f, "{}", e),
             Self::NameError(name) => write!(f, "variable [{}] is undefined", name),
             Self::FuncDefined(name) => write!(f, "function {} is already defined", name),
+            Self::ReservedName(name) => write!(f, "{} cannot be used as a command name", name),
+            Self::OptionalOrder(name) => {
+                write!(f, "argument {} must go before the optional arguments", name)
+            }
+            Self::RestOrder(name) => write!(f, "argument {}... must be the last one", name),
             Self::IsNotNumber(value) => write!(f, "{} is not a number", value),
             Self::IsNotList(value) => write!(f, "{} is not a list", value),
             Self::IOError(err) => write!(f, "io error: {}", err),
+            Self::ParseError(err) => write!(f, "parsing error: {}", err),
             Self::ZeroIndex => write!(f, "vurl is one-indexed, sadly"),
             Self::IndexError(index, len) => {
                 write!(f, "tried to use index {} of a list of {} items", index, len)
             }
+            Self::GridIndexError(what, index, len) => {
+                write!(f, "tried to use {} {}, but there are {}", what, index, len)
+            }
             Self::PopError => write!(f, "cannot pop from an empty list"),
+            Self::EmptyError => write!(f, "the list is empty"),
+            Self::SampleError(n, len) => {
+                write!(f, "cannot take {} items from a list of {}", n, len)
+            }
+            Self::RandomError(i, j) => write!(f, "there are no integers from {} to {}", i, j),
+            Self::SizeError(size) => write!(f, "size must be at least 1, not {}", size),
+            Self::TooLongError => write!(f, "the resulting string would be too long"),
+            Self::DepthError => write!(
+                f,
+                "lists nested more than {} deep can't be used here",
+                MAX_DEPTH
+            ),
+            Self::LengthError(l, m) => {
+                write!(f, "lists of {} and {} items have different lengths", l, m)
+            }
+            Self::CycleError => write!(f, "a list cannot contain itself"),
             Self::OrdError(s) => write!(f, "string \"{}\" must be one character long", s),
             Self::ChrError(i) => write!(f, "{} is not a valid unicode codepoint", i),
+            Self::ByteError(i) => write!(f, "{} is not a valid byte", i),
+            Self::Utf8Error(err) => write!(f, "{}", err),
+            Self::JsonError(pos, msg) => write!(f, "invalid json at character {}: {}", pos, msg),
+            Self::FormatError(msg) => write!(f, "cannot format: {}", msg),
+            Self::ClampError(lo, hi) => write!(f, "cannot clamp between {} and {}", lo, hi),
+            #[cfg(feature = "regex")]
+            Self::RegexError(err) => write!(f, "{}", err),
             Self::RandUnavailable => {
                 write!(f, "vurlrs was compiled without the feature `fastrand`")
             }
+            Self::RegexUnavailable => {
+                write!(f, "vurlrs was compiled without the feature `regex`")
+            }
         }
     }
 }
 impl Error for RunErrorKind {}
 
-impl fmt::Display for Value {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+/// lists nested deeper than this are shown as `(...)`, so printing them can't overflow the stack
+pub const MAX_DEPTH: usize = 256;
+/// after showing this many lists, the rest are shown as `(...)` too. a list that has the same
+/// list inside twice, and so on, takes exponentially long to show otherwise
+pub const MAX_LISTS: usize = 1 << 20;
+
+impl Value {
+    /// `left` is how many more lists can be shown
+    fn fmt_nested(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        depth: usize,
+        left: &mut usize,
+    ) -> fmt::Result {
         match self {
-            Value::String(s) => write!(f, "{}", s),
+            Value::List(_) if depth >= MAX_DEPTH || *left == 0 => write!(f, "(...)"),
             Value::List(v) => {
+                *left -= 1;
                 let borrow = v.borrow();
                 let mut iter = borrow.iter();
                 write!(f, "(")?;
                 if let Some(x) = iter.next() {
-                    write!(f, "{}", x)?
+                    x.fmt_nested(f, depth + 1, left)?
                 }
                 for i in iter {
-                    write!(f, ",{}", i)?;
+                    write!(f, ",")?;
+                    i.fmt_nested(f, depth + 1, left)?;
                 }
                 write!(f, ")")?;
                 Ok(())
             }
+            Value::Func(func) => func.fmt_nested(f, depth, left),
+            other => write!(f, "{}", other),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::String(s) => write!(f, "{}", s),
+            Value::List(_) => self.fmt_nested(f, 0, &mut { MAX_LISTS }),
             Value::Number(s) => write!(f, "{}", s),
+            Value::Func(func) => write!(f, "{}", func),
+            Value::Void => Ok(()),
             Value::Lineptr(lineno) => write!(f, "(line {})", lineno),
         }
     }
 }
 
-pub fn evaluate(state: &mut State, expr: &Expr) -> Result<Value, RunError> {
+pub fn evaluate_command(state: &mut State, cmd: &Command) -> Result<Value, RunError> {
+    let Command { name, args } = cmd;
+    // a `repeat` that is already running has the iterations left stored, so its count isn't
+    // evaluated again on every jump back to it
+    let running = &**name == "repeat" && state.repeats.contains_key(&state.lineno);
+    let args = (args.iter())
+        .map(|x| match x {
+            Expr::Lineptr(_) => evaluate(state, x, name),
+            _ if running => Ok(Value::Void),
+            _ => evaluate(state, x, name),
+        })
+        .collect::<Result<Vec<Value>, _>>()?;
+    execute_command(state, name, &args[..]).map_err(|x| RunError {
+        line: state.lineno,
+        function: Rc::clone(name),
+        inner: x,
+    })
+}
+
+/// evaluates an argument of the command `command`, which is the one blamed for undefined variables
+pub fn evaluate(state: &mut State, expr: &Expr, command: &Rc<str>) -> Result<Value, RunError> {
     match expr {
-        Expr::Command(Command { name, args }) => {
-            let args = (args.iter())
-                .map(|x| evaluate(state, x))
-                .collect::<Result<Vec<Value>, _>>()?;
-            execute_command(state, name, &args[..]).map_err(|x| RunError {
-                line: state.lineno,
-                function: Rc::from(name.as_str()),
-                inner: x,
-            })
-        }
-        Expr::Literal(s) => Ok(Value::String(Rc::from(s.as_str()))),
+        Expr::Command(cmd) => evaluate_command(state, cmd),
+        Expr::Literal(s) => Ok(Value::String(Rc::clone(s))),
         Expr::Number(n) => Ok(Value::Number(*n)),
         Expr::Variable(s) => {
             let var = if s.starts_with('.') {
@@ -155,35 +385,104 @@ pub fn evaluate(state: &mut State, expr: &Expr) -> Result<Value, RunError> {
             };
             var.cloned().ok_or_else(|| RunError {
                 line: state.lineno,
-                function: Rc::from("[]"),
+                function: Rc::clone(command),
                 inner: RunErrorKind::NameError(Rc::from(s.as_str())),
             })
         }
+        Expr::Interpolation(parts) => {
+            let mut string = String::new();
+            for part in parts {
+                write!(string, "{}", evaluate(state, part, command)?).unwrap();
+            }
+            Ok(Value::String(Rc::from(string)))
+        }
         Expr::Lineptr(l) => Ok(Value::Lineptr(*l)),
     }
 }
 
-pub fn execute(lines: &[Option<Command>]) -> Result<(), RunError> {
+/// runs a program, and returns its exit status
+pub fn execute(lines: Rc<[Option<Command>]>, path: Option<&Path>) -> Result<i32, RunError> {
+    // the main file counts as loaded, so a file it loads can't run it again
+    let loaded = path
+        .and_then(|p| p.canonicalize().ok())
+        .into_iter()
+        .collect();
     let mut state = State {
         globals: &mut HashMap::new(),
         locals: &mut HashMap::new(),
         functions: &mut HashMap::new(),
+        repeats: &mut HashMap::new(),
+        switches: &mut HashMap::new(),
+        loaded: &mut { loaded },
+        memos: &mut HashMap::new(),
+        calls: &mut HashMap::new(),
         lineno: 0,
         lines,
+        file: None,
     };
-    execute_with_state(&mut state)
+    match execute_with_state(&mut state) {
+        Ok(()) => Ok(0),
+        Err(e) => e.exit_code().ok_or(e),
+    }
 }
 
 pub fn execute_with_state(state: &mut State) -> Result<(), RunError> {
-    while state.lineno < state.lines.len() {
-        if let Some(cmd) = &state.lines[state.lineno] {
-            evaluate(state, &Expr::Command(cmd.to_owned()))?;
+    // a separate handle to `lines`, so commands can be borrowed from it while `state` is mutated
+    let lines = Rc::clone(&state.lines);
+    while state.lineno < lines.len() {
+        if let Some(cmd) = &lines[state.lineno] {
+            evaluate_command(state, cmd)?;
         }
-        state.lineno += 1;
+        state.lineno = state.lineno.wrapping_add(1);
     }
     Ok(())
 }
 
+/// writes a value so that two values get the same key only if `_eqstrict` says they are equal.
+/// json can't be used for this, since it makes NaN, the infinities and void all `null`. values
+/// nested too deeply, or with a key that would be too long, return `None` and are not memoized
+fn memo_key(v: &Value, out: &mut String, depth: usize) -> Option<()> {
+    if depth >= MAX_DEPTH || out.len() > MAX_MEMO_KEY {
+        return None;
+    }
+    match v {
+        Value::String(s) => write!(out, "{:?}", s).unwrap(),
+        // `0` and `-0` get different keys, but that only means calling it again
+        Value::Number(n) => write!(out, "{:?}", n).unwrap(),
+        Value::List(l) => {
+            out.push('[');
+            for item in l.borrow().iter() {
+                memo_key(item, out, depth + 1)?;
+            }
+            out.push(']');
+        }
+        Value::Func(func) => match func.as_ref() {
+            Callable::Named(name) => write!(out, "fn {:?}", name).unwrap(),
+            Callable::Compose(first, second) => {
+                out.push_str("compose(");
+                memo_key(first, out, depth + 1)?;
+                memo_key(second, out, depth + 1)?;
+                out.push(')');
+            }
+            Callable::Partial(func, args) => {
+                out.push_str("partial(");
+                memo_key(func, out, depth + 1)?;
+                for arg in args.iter() {
+                    memo_key(arg, out, depth + 1)?;
+                }
+                out.push(')');
+            }
+        },
+        Value::Void => out.push_str("void"),
+        Value::Lineptr(_) => unreachable!(),
+    }
+    out.push(' ');
+    Some(())
+}
+
+/// in bytes. lists that share their items can be much bigger than the memory they use
+const MAX_MEMO_KEY: usize = 1 << 20;
+
 pub fn execute_command(
     state: &mut State,
     name: &str,
@@ -193,6 +492,21 @@ pub fn execute_command(
         Err(RunErrorKind::IsNotBuiltIn) => (), // continue
         v => return v,
     }
+    // memoized functions only run the first time they get some arguments
+    let key = match state.memos.get(name) {
+        Some(memo) => {
+            let mut key = String::new();
+            let key = args
+                .iter()
+                .try_for_each(|arg| memo_key(arg, &mut key, 0))
+                .map(|()| key);
+            if let Some(v) = key.as_ref().and_then(|key| memo.get(key)) {
+                return Ok(v.clone());
+            }
+            key
+        }
+        None => None,
+    };
     let func = state.functions.get(name).ok_or(RunErrorKind::NotDefined)?;
     let mut locals = HashMap::from([(
         Rc::from(".args"),
@@ -200,28 +514,60 @@ pub fn execute_command(
     )]);
     if let Some(fargs) = &func.arguments {
         let fargs = Rc::clone(fargs);
-        if fargs.len() != args.len() {
-            return Err(RunErrorKind::ValueError(fargs.len()));
+        if func.rest.is_some() && args.len() < func.required {
+            return Err(RunErrorKind::ValueAtLeastError(func.required));
         }
-        for (k, v) in fargs.iter().zip(args) {
-            locals.insert(Rc::clone(k), v.clone());
+        if func.rest.is_none() && (args.len() < func.required || args.len() > fargs.len()) {
+            return Err(if func.required == fargs.len() {
+                RunErrorKind::ValueError(fargs.len())
+            } else {
+                RunErrorKind::ValueRangeError(func.required, fargs.len())
+            });
+        }
+        for (i, k) in fargs.iter().enumerate() {
+            locals.insert(Rc::clone(k), args.get(i).cloned().unwrap_or_default());
+        }
+        if let Some(rest) = &func.rest {
+            let rest_args = args.get(fargs.len()..).unwrap_or_default();
+            locals.insert(
+                Rc::clone(rest),
+                Value::List(Rc::from(RefCell::from(rest_args.to_vec()))),
+            );
         }
     }
+    let (file, caller_file) = (func.file.clone(), state.file.clone());
     let mut state = State {
         globals: state.globals,
         locals: &mut locals,
         lineno: func.lineno,
+        lines: Rc::clone(&func.lines),
+        file: file.clone(),
         functions: state.functions,
-        lines: state.lines,
+        repeats: &mut HashMap::new(),
+        switches: &mut HashMap::new(),
+        loaded: state.loaded,
+        memos: state.memos,
+        calls: state.calls,
     };
+    let lines = Rc::clone(&state.lines);
     loop {
-        if let Some(cmd) = &state.lines[state.lineno] {
-            match evaluate(&mut state, &Expr::Command(cmd.to_owned())) {
+        if let Some(cmd) = &lines[state.lineno] {
+            match evaluate_command(&mut state, cmd) {
                 Ok(_) => (),
                 Err(RunError {
                     inner: RunErrorKind::Return(v),
                     ..
-                }) => return Ok(v),
+                }) => {
+                    if let (Some(key), Some(memo)) = (key, state.memos.get_mut(name)) {
+                        memo.insert(key, v.clone());
+                    }
+                    return Ok(v);
+                }
+                // the lines of a function from another file can't be shown with the ones of
+                // this file, so the error has to say which one it is
+                Err(e) if file != caller_file => {
+                    return Err(RunErrorKind::InFile(file, Box::new(e)))
+                }
                 Err(e) => return Err(RunErrorKind::Wrap(Box::new(e))),
             };
         };